@@ -1,4 +1,9 @@
-use std::{fs::File, io::{BufRead, BufReader}};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::{mpsc, Arc, Mutex},
+};
 
 
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -7,34 +12,206 @@ enum Direction {
     Decreasing
 }
 
-fn are_levels_safe(levels: &[u32]) -> bool {
-    // Levels must all be either increasing or decreasing.
-    // Delta between levels MUST be: 1 <= level <= 3.
-    println!("--- Checking safety of {levels:?}");
-    let mut previous_level = levels[0];
+impl Direction {
+    /// Direction of the step from `from` to `to`, `None` for a flat step.
+    fn of(from: u32, to: u32) -> Option<Direction> {
+        match to.cmp(&from) {
+            std::cmp::Ordering::Greater => Some(Direction::Increasing),
+            std::cmp::Ordering::Less => Some(Direction::Decreasing),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    fn reversed(self) -> Direction {
+        match self {
+            Direction::Increasing => Direction::Decreasing,
+            Direction::Decreasing => Direction::Increasing,
+        }
+    }
+}
+
+/// Directions a report is allowed to move in.
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Directions {
+    /// Every step must go up.
+    Increasing,
+    /// Every step must go down.
+    Decreasing,
+    /// Every step must go the same way as the first one, be it up or down.
+    Either,
+    /// Steps may change direction freely, only their size is checked.
+    NonStrict,
+}
+
+impl std::str::FromStr for Directions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "increasing" => Ok(Directions::Increasing),
+            "decreasing" => Ok(Directions::Decreasing),
+            "either" => Ok(Directions::Either),
+            "non-strict" => Ok(Directions::NonStrict),
+            _ => Err(format!("unknown directions: {s}")),
+        }
+    }
+}
+
+/// Rules a report must follow to be considered safe.
+///
+/// Flat steps have no direction, so they are governed by `min_step` alone.
+#[derive(PartialEq, Debug, Copy, Clone)]
+struct ReportPolicy {
+    /// Smallest allowed delta between adjacent levels.
+    min_step: u32,
+    /// Largest allowed delta between adjacent levels.
+    max_step: u32,
+    directions: Directions,
+}
+
+impl ReportPolicy {
+    /// The puzzle's rules: deltas of 1..=3, strictly increasing or strictly decreasing.
+    const PUZZLE: ReportPolicy = ReportPolicy {
+        min_step: 1,
+        max_step: 3,
+        directions: Directions::Either,
+    };
+
+    fn step_in_range(&self, delta: u32) -> bool {
+        (self.min_step..=self.max_step).contains(&delta)
+    }
+
+    /// Checks whether a step in `direction` is allowed, given the direction the report has settled on so far.
+    fn direction_allowed(&self, settled: Option<Direction>, direction: Option<Direction>) -> bool {
+        let Some(direction) = direction else {
+            return true;
+        };
+        match self.directions {
+            Directions::Increasing => direction == Direction::Increasing,
+            Directions::Decreasing => direction == Direction::Decreasing,
+            Directions::Either => settled.is_none_or(|settled| settled == direction),
+            Directions::NonStrict => true,
+        }
+    }
+
+    /// Reads a policy from `min_step max_step directions` arguments,
+    /// falling back to the puzzle's rules when none are given.
+    fn from_args(mut args: impl Iterator<Item = String>) -> Self {
+        let Some(min_step) = args.next() else {
+            return ReportPolicy::default();
+        };
+        ReportPolicy {
+            min_step: min_step.parse().unwrap(),
+            max_step: args.next().unwrap().parse().unwrap(),
+            directions: args.next().unwrap().parse().unwrap(),
+        }
+    }
+}
+
+impl Default for ReportPolicy {
+    fn default() -> Self {
+        ReportPolicy::PUZZLE
+    }
+}
+
+/// The first rule a report breaks.
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Violation {
+    /// Report has no levels at all.
+    TooShort,
+    /// Delta between adjacent levels is below the policy's `min_step`.
+    DeltaTooSmall(u32),
+    /// Delta between adjacent levels is above the policy's `max_step`.
+    DeltaTooLarge(u32),
+    /// Step goes in a direction the policy doesn't allow at this point.
+    DirectionFlip,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::TooShort => write!(f, "report too short"),
+            Violation::DeltaTooSmall(delta) => write!(f, "delta {delta} too small"),
+            Violation::DeltaTooLarge(delta) => write!(f, "delta {delta} too large"),
+            Violation::DirectionFlip => write!(f, "direction flip"),
+        }
+    }
+}
+
+impl Violation {
+    fn kind(&self) -> ViolationKind {
+        match self {
+            Violation::TooShort => ViolationKind::TooShort,
+            Violation::DeltaTooSmall(_) => ViolationKind::DeltaTooSmall,
+            Violation::DeltaTooLarge(_) => ViolationKind::DeltaTooLarge,
+            Violation::DirectionFlip => ViolationKind::DirectionFlip,
+        }
+    }
+}
+
+/// [Violation] without the details, for tallying.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Copy, Clone)]
+enum ViolationKind {
+    TooShort,
+    DeltaTooSmall,
+    DeltaTooLarge,
+    DirectionFlip,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum SafetyVerdict {
+    Safe,
+    /// Report is unsafe, `index` is the level at which the `violation` happened.
+    Unsafe {
+        violation: Violation,
+        index: usize,
+    },
+}
+
+impl SafetyVerdict {
+    fn is_safe(&self) -> bool {
+        *self == SafetyVerdict::Safe
+    }
+}
+
+/// Checks `levels` against `policy`, reporting the first violation if there is one.
+fn are_levels_safe(levels: &[u32], policy: &ReportPolicy) -> SafetyVerdict {
+    // Every delta between levels must be within the policy's step range,
+    // and every step must go in a direction the policy allows.
+    let Some(&first) = levels.first() else {
+        return SafetyVerdict::Unsafe {
+            violation: Violation::TooShort,
+            index: 0,
+        };
+    };
+    let mut previous_level = first;
     let mut previous_direction = None;
-    for lvl in &levels[1..] {
-        println!("lvl: {lvl}, previous_level: {previous_level}, previous_direction: {previous_direction:?}");
-        let lvl = *lvl;
+    for (index, &lvl) in levels.iter().enumerate().skip(1) {
         let delta = previous_level.abs_diff(lvl);
-        if delta < 1 || delta > 3 {
-            return false;
-        }
-        if let Some(prev_dir) = previous_direction {
-            if lvl > previous_level && prev_dir == Direction::Decreasing {
-                return false;
-            } else if lvl < previous_level && prev_dir == Direction::Increasing {
-                return false;
-            }
-        } else if lvl > previous_level {
-            previous_direction = Some(Direction::Increasing);
+        let violation = if delta < policy.min_step {
+            Some(Violation::DeltaTooSmall(delta))
+        } else if delta > policy.max_step {
+            Some(Violation::DeltaTooLarge(delta))
         } else {
-            previous_direction = Some(Direction::Decreasing);
+            None
+        };
+        if let Some(violation) = violation {
+            return SafetyVerdict::Unsafe { violation, index };
+        }
+        let direction = Direction::of(previous_level, lvl);
+        if !policy.direction_allowed(previous_direction, direction) {
+            return SafetyVerdict::Unsafe {
+                violation: Violation::DirectionFlip,
+                index,
+            };
+        }
+        if previous_direction.is_none() {
+            previous_direction = direction;
         }
         previous_level = lvl;
     }
 
-    true
+    SafetyVerdict::Safe
 }
 
 #[test]
@@ -49,28 +226,198 @@ fn test_levels() {
     ];
 
     for (case, expected) in levels {
-        let safety = are_levels_safe(&case);
+        let safety = are_levels_safe(&case, &ReportPolicy::default()).is_safe();
         assert_eq!(safety, expected, "levels: {case:?}");
     }
 }
 
+#[test]
+fn test_verdicts() {
+    let policy = ReportPolicy::default();
+    let unsafe_at = |violation, index| SafetyVerdict::Unsafe { violation, index };
+    let levels = vec![
+        (vec![7, 6, 4, 2, 1], SafetyVerdict::Safe),
+        (
+            vec![1, 2, 7, 8, 9],
+            unsafe_at(Violation::DeltaTooLarge(5), 2),
+        ),
+        (
+            vec![9, 7, 6, 2, 1],
+            unsafe_at(Violation::DeltaTooLarge(4), 3),
+        ),
+        (vec![1, 3, 2, 4, 5], unsafe_at(Violation::DirectionFlip, 2)),
+        (
+            vec![8, 6, 4, 4, 1],
+            unsafe_at(Violation::DeltaTooSmall(0), 3),
+        ),
+        (vec![], unsafe_at(Violation::TooShort, 0)),
+        (vec![5], SafetyVerdict::Safe),
+    ];
+
+    for (case, expected) in levels {
+        let verdict = are_levels_safe(&case, &policy);
+        assert_eq!(verdict, expected, "levels: {case:?}");
+    }
+}
+
 /// Part2.
-fn are_levels_safe_dampened(levels: &[u32]) -> bool {
-    // Level reports are not too long.
-    // In case of unsafety, just remove an element and try again.
-    if are_levels_safe(levels) {
+fn are_levels_safe_dampened(levels: &[u32], policy: &ReportPolicy) -> bool {
+    // The problem dampener tolerates a single bad level.
+    if are_levels_safe(levels, policy).is_safe() {
         return true;
     }
-    // Levels are not safe out of the box, apply problem dampener.
-    // Try to remove 1 level at a time to see if the remaining levels are ok.
-    for idx in 0..levels.len() {
-        let mut dampened = levels.to_vec();
-        dampened.remove(idx);
-        if are_levels_safe(&dampened) {
-            return true;
+    dampen(levels, policy, 1).is_some()
+}
+
+/// Settled direction of a report as a DP index: nothing yet, increasing, decreasing.
+fn settled_idx(direction: Option<Direction>) -> usize {
+    match direction {
+        None => 0,
+        Some(Direction::Increasing) => 1,
+        Some(Direction::Decreasing) => 2,
+    }
+}
+
+fn settled_dir(idx: usize) -> Option<Direction> {
+    [
+        None,
+        Some(Direction::Increasing),
+        Some(Direction::Decreasing),
+    ][idx]
+}
+
+/// Finds the fewest levels, at most `tolerance` of them, that have to be removed
+/// for `levels` to be safe under `policy`.
+/// Returns the removed indices in ascending order, or `None` if no such removal exists.
+///
+/// Runs a DP over (last kept level, removals used, settled direction).
+/// Only up to `tolerance` levels can be skipped between two kept ones,
+/// so this is O(n * k^2) per report instead of retrying every possible removal.
+fn dampen(levels: &[u32], policy: &ReportPolicy, tolerance: usize) -> Option<Vec<usize>> {
+    let n = levels.len();
+    if n == 0 {
+        return None;
+    }
+    // reached[i][r][d] is set if we can keep level `i` as the latest level,
+    // having removed `r` levels so far and settled on direction `d`.
+    // It holds the previously kept level and its direction, `None` if `i` is the first kept level.
+    let mut reached = vec![vec![[None; 3]; tolerance + 1]; n];
+    // Any of the first `tolerance + 1` levels can start the report, removing everything before it.
+    for (first, removals) in reached.iter_mut().take(tolerance + 1).enumerate() {
+        removals[first][0] = Some(None);
+    }
+    for i in 0..n {
+        for r in 0..=tolerance {
+            for d in 0..3 {
+                if reached[i][r][d].is_none() {
+                    continue;
+                }
+                for next in i + 1..n {
+                    let removed = r + next - i - 1;
+                    if removed > tolerance {
+                        break;
+                    }
+                    if !policy.step_in_range(levels[i].abs_diff(levels[next])) {
+                        continue;
+                    }
+                    let direction = Direction::of(levels[i], levels[next]);
+                    if !policy.direction_allowed(settled_dir(d), direction) {
+                        continue;
+                    }
+                    let next_d = if d == 0 { settled_idx(direction) } else { d };
+                    let state = &mut reached[next][removed][next_d];
+                    if state.is_none() {
+                        *state = Some(Some((i, d)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Pick the last kept level which leaves the fewest removals in total.
+    let mut best = None;
+    for (i, removals) in reached.iter().enumerate() {
+        for (r, dirs) in removals.iter().enumerate() {
+            let total = r + n - 1 - i;
+            if total > tolerance || best.is_some_and(|(best_total, _, _, _)| best_total <= total) {
+                continue;
+            }
+            if let Some(d) = dirs.iter().position(|state| state.is_some()) {
+                best = Some((total, i, r, d));
+            }
         }
     }
-    false
+    let (_, mut i, mut r, mut d) = best?;
+
+    // Walk the kept levels back to the start, everything else was removed.
+    let mut kept = vec![false; n];
+    loop {
+        kept[i] = true;
+        let Some((prev, prev_d)) = reached[i][r][d].unwrap() else {
+            break;
+        };
+        r -= i - prev - 1;
+        i = prev;
+        d = prev_d;
+    }
+    Some((0..n).filter(|&idx| !kept[idx]).collect())
+}
+
+#[test]
+fn test_dampen() {
+    let policy = ReportPolicy::default();
+    assert_eq!(dampen(&[7, 6, 4, 2, 1], &policy, 1), Some(vec![]));
+    assert_eq!(dampen(&[1, 5, 2, 3, 4], &policy, 1), Some(vec![1]));
+    // Either of the repeated 4s can go.
+    assert_eq!(
+        dampen(&[8, 6, 4, 4, 1], &policy, 1).map(|removed| removed.len()),
+        Some(1)
+    );
+    assert_eq!(dampen(&[1, 2, 7, 8, 9], &policy, 1), None);
+    assert_eq!(dampen(&[9, 1, 2, 3], &policy, 1), Some(vec![0]));
+    assert_eq!(dampen(&[1, 2, 3, 9], &policy, 1), Some(vec![3]));
+    // Two bad levels in a row need a bigger tolerance.
+    assert_eq!(dampen(&[1, 2, 9, 10, 3, 4], &policy, 1), None);
+    assert_eq!(dampen(&[1, 2, 9, 10, 3, 4], &policy, 2), Some(vec![2, 3]));
+    assert_eq!(
+        dampen(&[5, 1, 2, 9, 3, 4, 0], &policy, 3),
+        Some(vec![0, 3, 6])
+    );
+    assert_eq!(dampen(&[], &policy, 1), None);
+}
+
+#[test]
+fn test_levels_policy() {
+    let sensor = ReportPolicy {
+        min_step: 0,
+        max_step: 5,
+        directions: Directions::Increasing,
+    };
+    let levels = vec![
+        (vec![1, 1, 6, 8, 8], true),
+        (vec![1, 2, 8, 9], false),
+        (vec![9, 7, 6, 2, 1], false),
+        (vec![3], true),
+    ];
+    for (case, expected) in levels {
+        let safety = are_levels_safe(&case, &sensor).is_safe();
+        assert_eq!(safety, expected, "levels: {case:?}");
+    }
+
+    let wobbly = ReportPolicy {
+        directions: Directions::NonStrict,
+        ..ReportPolicy::PUZZLE
+    };
+    assert!(are_levels_safe(&[1, 3, 2, 4, 5], &wobbly).is_safe());
+    assert!(are_levels_safe(
+        &[9, 7, 6, 4],
+        &ReportPolicy {
+            directions: "decreasing".parse().unwrap(),
+            ..ReportPolicy::PUZZLE
+        }
+    )
+    .is_safe());
+    assert!(!are_levels_safe(&[8, 6, 4, 4, 1], &wobbly).is_safe());
 }
 
 #[test]
@@ -85,27 +432,454 @@ fn test_levels_dampened() {
     ];
 
     for (case, expected) in levels {
-        let safety = are_levels_safe_dampened(&case);
+        let safety = are_levels_safe_dampened(&case, &ReportPolicy::default());
         assert_eq!(safety, expected, "levels: {case:?}");
     }
 }
 
+/// A report made safe by changing as few level values as possible.
+#[derive(PartialEq, Debug, Clone)]
+struct Repair {
+    /// Indices of the levels whose values had to change.
+    changed: Vec<usize>,
+    levels: Vec<u32>,
+}
+
+/// Turns signed `steps` taken from `start` into levels, failing if any of them leaves the `u32` range.
+fn walk(start: u32, steps: impl Iterator<Item = i64>) -> Option<Vec<u32>> {
+    let mut level = start as i64;
+    steps
+        .map(|step| {
+            level += step;
+            u32::try_from(level).ok()
+        })
+        .collect()
+}
+
+/// Finds the levels strictly between `from` and `to` that bridge them in exactly `steps` valid steps,
+/// all going in `direction`, or in any direction if it's `None`.
+fn bridge(
+    from: u32,
+    to: u32,
+    steps: usize,
+    direction: Option<Direction>,
+    policy: &ReportPolicy,
+) -> Option<Vec<u32>> {
+    let (min, max) = (policy.min_step as i64, policy.max_step as i64);
+    let diff = to as i64 - from as i64;
+    let m = steps as i64;
+    // Start every step at its smallest magnitude, then hand out the `extra` distance still missing.
+    let (ups, downs, mut extra) = match direction {
+        Some(Direction::Increasing) => (m, 0, diff - m * min),
+        Some(Direction::Decreasing) => (0, m, -diff - m * min),
+        None => {
+            // Go up `ups` times first, then down for the rest.
+            // Starting with ups at their smallest and downs at their largest gives the lowest reachable level.
+            let ups = (0..=m).find(|&ups| {
+                let downs = m - ups;
+                ups * min - downs * max <= diff && diff <= ups * max - downs * min
+            })?;
+            (ups, m - ups, diff - (ups * min - (m - ups) * max))
+        }
+    };
+    if extra < 0 || extra > m * (max - min) {
+        return None;
+    }
+    let mut take_extra = move || {
+        let add = extra.min(max - min);
+        extra -= add;
+        add
+    };
+    let up_steps: Vec<i64> = (0..ups).map(|_| min + take_extra()).collect();
+    let down_steps: Vec<i64> = (0..downs)
+        .map(|_| match direction {
+            Some(_) => -(min + take_extra()),
+            None => -(max - take_extra()),
+        })
+        .collect();
+    let mut levels = walk(from, up_steps.into_iter().chain(down_steps))?;
+    levels.pop();
+    Some(levels)
+}
+
+/// Makes up `count` levels following `anchor`, going in `direction`, or wobbling in place if it's `None`.
+fn extend(
+    anchor: u32,
+    count: usize,
+    direction: Option<Direction>,
+    policy: &ReportPolicy,
+) -> Option<Vec<u32>> {
+    let min = policy.min_step as i64;
+    let steps = (0..count as i64).map(|k| match direction {
+        Some(Direction::Increasing) => min,
+        Some(Direction::Decreasing) => -min,
+        None if k % 2 == 0 => min,
+        None => -min,
+    });
+    walk(anchor, steps)
+}
+
+/// Finds the fewest level values to change for `levels` to be safe under `policy`,
+/// refusing repairs that need more than `budget` changes.
+///
+/// The levels left as they are form a chain in which every gap between neighbours
+/// can be bridged by changed levels, so this looks for the longest such chain.
+fn repair(levels: &[u32], policy: &ReportPolicy, budget: usize) -> Option<Repair> {
+    if levels.is_empty() || policy.min_step > policy.max_step {
+        return None;
+    }
+    let directions: &[Option<Direction>] = match policy.directions {
+        Directions::Increasing => &[Some(Direction::Increasing)],
+        Directions::Decreasing => &[Some(Direction::Decreasing)],
+        Directions::Either => &[Some(Direction::Increasing), Some(Direction::Decreasing)],
+        Directions::NonStrict => &[None],
+    };
+    directions
+        .iter()
+        .filter_map(|&direction| repair_towards(levels, policy, direction))
+        .min_by_key(|repair| repair.changed.len())
+        .filter(|repair| repair.changed.len() <= budget)
+}
+
+fn repair_towards(
+    levels: &[u32],
+    policy: &ReportPolicy,
+    direction: Option<Direction>,
+) -> Option<Repair> {
+    let n = levels.len();
+    let backwards = direction.map(Direction::reversed);
+    // chains[j] is the longest chain of kept levels ending at level `j`, with the previous kept level.
+    let mut chains: Vec<Option<(usize, Option<usize>)>> = vec![None; n];
+    for j in 0..n {
+        if extend(levels[j], j, backwards, policy).is_some() {
+            chains[j] = Some((1, None));
+        }
+        for i in 0..j {
+            let Some((len, _)) = chains[i] else {
+                continue;
+            };
+            if chains[j].is_some_and(|(best, _)| best > len) {
+                continue;
+            }
+            if bridge(levels[i], levels[j], j - i, direction, policy).is_some() {
+                chains[j] = Some((len + 1, Some(i)));
+            }
+        }
+    }
+    let last = (0..n)
+        .filter(|&j| extend(levels[j], n - 1 - j, direction, policy).is_some())
+        .filter_map(|j| chains[j].map(|(len, _)| (len, j)))
+        .max()?
+        .1;
+
+    let mut kept = vec![last];
+    while let Some((_, Some(prev))) = chains[*kept.last().unwrap()] {
+        kept.push(prev);
+    }
+    kept.reverse();
+
+    let first = kept[0];
+    let mut repaired = extend(levels[first], first, backwards, policy)?;
+    repaired.reverse();
+    for pair in kept.windows(2) {
+        repaired.push(levels[pair[0]]);
+        let (from, to) = (pair[0], pair[1]);
+        let bridged = bridge(levels[from], levels[to], to - from, direction, policy)?;
+        repaired.extend(bridged);
+    }
+    repaired.push(levels[last]);
+    repaired.extend(extend(levels[last], n - 1 - last, direction, policy)?);
+
+    let changed = (0..n).filter(|&idx| repaired[idx] != levels[idx]).collect();
+    Some(Repair {
+        changed,
+        levels: repaired,
+    })
+}
+
+#[test]
+fn test_repair() {
+    let policy = ReportPolicy::default();
+    let cases = vec![
+        (vec![7, 6, 4, 2, 1], 0),
+        (vec![1, 2, 7, 8, 9], 1),
+        (vec![9, 7, 6, 2, 1], 1),
+        (vec![1, 3, 2, 4, 5], 2),
+        (vec![8, 6, 4, 4, 1], 1),
+        (vec![0, 0, 0, 0], 3),
+        (vec![1, 20, 3, 21, 5], 2),
+    ];
+    for (case, changes) in cases {
+        let repair = repair(&case, &policy, usize::MAX).unwrap();
+        assert_eq!(repair.changed.len(), changes, "levels: {case:?}");
+        let verdict = are_levels_safe(&repair.levels, &policy);
+        assert!(verdict.is_safe(), "{repair:?}");
+    }
+    // Over budget.
+    assert_eq!(repair(&[1, 2, 7, 12, 13], &policy, 1), None);
+    assert_eq!(repair(&[], &policy, 1), None);
+
+    let wobbly = ReportPolicy {
+        directions: Directions::NonStrict,
+        ..ReportPolicy::PUZZLE
+    };
+    let repair = repair(&[5, 5, 5, 5, 0], &wobbly, 5).unwrap();
+    assert_eq!(repair.changed, vec![1, 3]);
+    let verdict = are_levels_safe(&repair.levels, &wobbly);
+    assert!(verdict.is_safe(), "{repair:?}");
+}
+
+/// Aggregate statistics over a batch of reports.
+#[derive(PartialEq, Debug, Default)]
+struct ReportStats {
+    safe: usize,
+    /// Reports that are unsafe, but made safe by the problem dampener.
+    dampened_safe: usize,
+    /// Reports that are unsafe even with the problem dampener.
+    unsafe_reports: usize,
+    /// How often each delta between adjacent levels occurs.
+    deltas: BTreeMap<u32, usize>,
+    /// How many reports there are of each length.
+    lengths: BTreeMap<usize, usize>,
+    /// How often each kind of violation is the first one in a report.
+    violations: HashMap<ViolationKind, usize>,
+    /// Lines that aren't a list of levels, left out of everything else.
+    malformed: usize,
+}
+
+impl ReportStats {
+    fn add(&mut self, levels: &[u32], policy: &ReportPolicy) {
+        *self.lengths.entry(levels.len()).or_default() += 1;
+        for pair in levels.windows(2) {
+            *self.deltas.entry(pair[0].abs_diff(pair[1])).or_default() += 1;
+        }
+        match are_levels_safe(levels, policy) {
+            SafetyVerdict::Safe => self.safe += 1,
+            SafetyVerdict::Unsafe { violation, .. } => {
+                *self.violations.entry(violation.kind()).or_default() += 1;
+                if dampen(levels, policy, 1).is_some() {
+                    self.dampened_safe += 1;
+                } else {
+                    self.unsafe_reports += 1;
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, other: ReportStats) {
+        self.safe += other.safe;
+        self.dampened_safe += other.dampened_safe;
+        self.unsafe_reports += other.unsafe_reports;
+        for (delta, count) in other.deltas {
+            *self.deltas.entry(delta).or_default() += count;
+        }
+        for (len, count) in other.lengths {
+            *self.lengths.entry(len).or_default() += count;
+        }
+        for (kind, count) in other.violations {
+            *self.violations.entry(kind).or_default() += count;
+        }
+        self.malformed += other.malformed;
+    }
+
+    /// Most common kind of violation, ties going to the one declared first.
+    fn most_common_violation(&self) -> Option<ViolationKind> {
+        self.violations
+            .iter()
+            .max_by_key(|(&kind, &count)| (count, std::cmp::Reverse(kind)))
+            .map(|(&kind, _)| kind)
+    }
+}
+
+/// How many lines a worker gets at a time in [evaluate_batch].
+const BATCH_LINES: usize = 4096;
+
+/// Evaluates every report in `reader` on `threads` worker threads.
+///
+/// Lines are handed out in batches over a bounded channel,
+/// so only a few batches are ever held in memory regardless of input size.
+fn evaluate_batch(reader: impl BufRead, policy: &ReportPolicy, threads: usize) -> ReportStats {
+    let threads = threads.max(1);
+    let (sender, receiver) = mpsc::sync_channel::<Vec<Vec<u8>>>(threads * 2);
+    // Workers own the receiver between them, so if they all die it's dropped,
+    // and sending fails instead of blocking forever on a full channel.
+    let receiver = Arc::new(Mutex::new(receiver));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                scope.spawn(move || {
+                    let mut stats = ReportStats::default();
+                    loop {
+                        // Only hold the lock while waiting for the next batch.
+                        let Ok(batch) = receiver.lock().unwrap().recv() else {
+                            break;
+                        };
+                        // Lines that aren't UTF-8 are as malformed as ones without numbers.
+                        for line in batch {
+                            match std::str::from_utf8(&line).map(try_parse_report) {
+                                Ok(Ok(levels)) => stats.add(&levels, policy),
+                                _ => stats.malformed += 1,
+                            }
+                        }
+                    }
+                    stats
+                })
+            })
+            .collect();
+        drop(receiver);
+
+        // Sending only fails once every worker is gone, and joining them reports why.
+        let mut batch = Vec::with_capacity(BATCH_LINES);
+        for line in reader.split(b'\n') {
+            batch.push(line.unwrap());
+            if batch.len() == BATCH_LINES {
+                let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_LINES));
+                if sender.send(full).is_err() {
+                    break;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            let _ = sender.send(batch);
+        }
+        // Closing the channel lets the workers finish.
+        drop(sender);
+
+        let mut stats = ReportStats::default();
+        for worker in workers {
+            stats.merge(worker.join().unwrap());
+        }
+        stats
+    })
+}
+
+#[cfg(test)]
+const TEST_REPORTS: &str = r#"7 6 4 2 1
+1 2 7 8 9
+9 7 6 2 1
+1 3 2 4 5
+8 6 4 4 1
+1 3 6 7 9"#;
+
+#[test]
+fn test_evaluate_batch() {
+    let policy = ReportPolicy::default();
+    let stats = evaluate_batch(TEST_REPORTS.as_bytes(), &policy, 4);
+    assert_eq!(stats.safe, 2);
+    assert_eq!(stats.dampened_safe, 2);
+    assert_eq!(stats.unsafe_reports, 2);
+    assert_eq!(stats.lengths, BTreeMap::from([(5, 6)]));
+    assert_eq!(stats.deltas[&0], 1);
+    assert_eq!(stats.deltas[&5], 1);
+    assert_eq!(stats.deltas.values().sum::<usize>(), 24);
+    assert_eq!(
+        stats.most_common_violation(),
+        Some(ViolationKind::DeltaTooLarge)
+    );
+
+    // Enough reports to spread over several batches.
+    let big = format!("{TEST_REPORTS}\n").repeat(BATCH_LINES);
+    let single = evaluate_batch(big.as_bytes(), &policy, 1);
+    let parallel = evaluate_batch(big.as_bytes(), &policy, 4);
+    assert_eq!(single, parallel);
+    assert_eq!(parallel.safe, 2 * BATCH_LINES);
+    assert_eq!(parallel.malformed, 0);
+
+    // A bad line is counted, not fatal, even with a single worker.
+    let bad = format!("1 2 x\n{big}");
+    let stats = evaluate_batch(bad.as_bytes(), &policy, 1);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.safe, 2 * BATCH_LINES);
+    let mut bad = b"1 2 \xff\n".to_vec();
+    bad.extend_from_slice(b"7 6 4 2 1\r\n");
+    let stats = evaluate_batch(bad.as_slice(), &policy, 2);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.safe, 1);
+}
+
+/// Parses a line into a levels report.
+fn parse_report(line: &str) -> Vec<u32> {
+    try_parse_report(line).unwrap()
+}
+
+/// Like [parse_report], but reports levels that aren't numbers instead of panicking.
+fn try_parse_report(line: &str) -> Result<Vec<u32>, std::num::ParseIntError> {
+    line.split_ascii_whitespace()
+        .map(|chunk| chunk.parse())
+        .collect()
+}
+
 fn main() {
     let path = "day2/input.txt";
     let buf_read = BufReader::new(File::open(path).unwrap());
+    // Flags tweak the output, the rest of the args configure the policy.
+    let (flags, args): (Vec<String>, Vec<String>) = std::env::args()
+        .skip(1)
+        .partition(|arg| arg.starts_with("--"));
+    // `--explain` prints why each unsafe report is unsafe.
+    let explain = flags.iter().any(|flag| flag == "--explain");
+    // `--repair=N` suggests a repair of at most N changed levels for each unsafe report.
+    let repair_budget: Option<usize> = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--repair="))
+        .map(|budget| budget.parse().unwrap());
+    let policy = ReportPolicy::from_args(args.into_iter());
+    // `--batch` evaluates the reports on all cores and prints statistics instead.
+    if flags.iter().any(|flag| flag == "--batch") {
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let stats = evaluate_batch(buf_read, &policy, threads);
+        println!("Safe reports: {}", stats.safe);
+        println!(
+            "Safe reports with dampener: {}",
+            stats.safe + stats.dampened_safe
+        );
+        println!("Unsafe reports: {}", stats.unsafe_reports);
+        println!("Most common violation: {:?}", stats.most_common_violation());
+        println!("Malformed lines: {}", stats.malformed);
+        println!("Deltas:");
+        for (delta, count) in &stats.deltas {
+            println!("  {delta}: {count}");
+        }
+        println!("Report lengths:");
+        for (len, count) in &stats.lengths {
+            println!("  {len}: {count}");
+        }
+        return;
+    }
     let mut safe_levels = 0u32;
-    for line in buf_read.lines() {
+    let mut safe_levels_dampened = 0u32;
+    for (line_no, line) in buf_read.lines().enumerate() {
         let line = line.unwrap();
-        // Parse line into levels report.
-        let report: Vec<u32> = line.split_ascii_whitespace().map(|chunk| chunk.parse().unwrap()).collect();
+        let report = parse_report(&line);
         // Part 1
-        // if are_levels_safe(&report) {
-        //     safe_levels += 1;
-        // }
+        match are_levels_safe(&report, &policy) {
+            SafetyVerdict::Safe => safe_levels += 1,
+            SafetyVerdict::Unsafe { violation, index } => {
+                if explain {
+                    println!("line {}: {line}: {violation} at level {index}", line_no + 1);
+                }
+                if let Some(budget) = repair_budget {
+                    match repair(&report, &policy, budget) {
+                        Some(repair) => println!(
+                            "line {}: {line}: repaired to {:?} by changing levels {:?}",
+                            line_no + 1,
+                            repair.levels,
+                            repair.changed
+                        ),
+                        None => println!(
+                            "line {}: {line}: can't be repaired with {budget} changes",
+                            line_no + 1
+                        ),
+                    }
+                }
+            }
+        }
         // Part 2
-        if are_levels_safe_dampened(&report) {
-            safe_levels += 1;
+        if are_levels_safe_dampened(&report, &policy) {
+            safe_levels_dampened += 1;
         }
     }
     println!("Safe reports: {safe_levels}");
+    println!("Safe reports with dampener: {safe_levels_dampened}");
 }
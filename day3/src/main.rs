@@ -2,28 +2,147 @@
 // Let's try using `nom` for this purpose.
 // We choose `nom`, because it defines parsers as rust functions, which is appealing for quickhax.
 
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    ops::Range,
+};
+
 use nom::{
     branch::alt,
-    bytes::{tag, take},
-    character::complete::{char, digit1},
-    combinator::{map, map_res, value},
-    error::VerboseError,
+    bytes::{tag, take, take_while1},
+    character::complete::{char, digit1, multispace0, space0},
+    combinator::{all_consuming, map, opt, value},
+    error::{ErrorKind, ParseError, VerboseError, VerboseErrorKind},
+    multi::separated_list0,
     sequence::{delimited, preceded, separated_pair},
     IResult, Parser,
 };
 
+/// Longest an operand can be in any grammar, counting its sign and the whitespace around it.
+/// Leading zeros could make it any length otherwise, and [OpStream] has to hold whole ops.
+const MAX_OPERAND_LEN: usize = 1024;
+
+/// How `mul` operands are read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grammar {
+    /// Most digits an operand can have, `None` for any amount that fits into an `i32`.
+    /// Either way, no more than [MAX_OPERAND_LEN] in all.
+    pub max_digits: Option<usize>,
+    /// Whether operands can have a leading `-`.
+    pub signed: bool,
+    /// Whether whitespace is allowed around operands.
+    pub whitespace: bool,
+}
+
+impl Grammar {
+    /// The puzzle's rules: non-negative operands of 1 to 3 digits.
+    pub const PUZZLE: Grammar = Grammar {
+        max_digits: Some(3),
+        signed: false,
+        whitespace: false,
+    };
+    /// Anything that fits into an `i32`, as [parse_num] has always read it.
+    pub const PERMISSIVE: Grammar = Grammar {
+        max_digits: None,
+        signed: true,
+        whitespace: false,
+    };
+}
+
+impl std::str::FromStr for Grammar {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "puzzle" => Ok(Grammar::PUZZLE),
+            "permissive" => Ok(Grammar::PERMISSIVE),
+            _ => Err(format!("unknown grammar: {s}")),
+        }
+    }
+}
+
+fn num<'a>(grammar: Grammar) -> impl Parser<&'a str, Output = i32, Error = VerboseError<&'a str>> {
+    move |input: &'a str| {
+        let (i, _) = if grammar.whitespace {
+            multispace0(input)?
+        } else {
+            (input, "")
+        };
+        let (digits_start, minus) = if grammar.signed {
+            opt(tag("-")).parse(i)?
+        } else {
+            (i, None)
+        };
+        let (rem, digits) = digit1(digits_start)?;
+        let too_long = grammar.max_digits.is_some_and(|max| digits.len() > max);
+        let Ok(num) = digits.parse::<i32>() else {
+            return Err(nom::Err::Error(VerboseError::from_error_kind(
+                digits_start,
+                ErrorKind::MapRes,
+            )));
+        };
+        if too_long {
+            return Err(nom::Err::Error(VerboseError::from_error_kind(
+                digits_start,
+                ErrorKind::TooLarge,
+            )));
+        }
+        let (rem, _) = if grammar.whitespace {
+            multispace0(rem)?
+        } else {
+            (rem, "")
+        };
+        if input.len() - rem.len() > MAX_OPERAND_LEN {
+            return Err(nom::Err::Error(VerboseError::from_error_kind(
+                digits_start,
+                ErrorKind::TooLarge,
+            )));
+        }
+        Ok((rem, if minus.is_some() { -num } else { num }))
+    }
+}
+
 fn parse_num(i: &str) -> IResult<&str, i32, VerboseError<&str>> {
-    let pos_num = map_res(digit1, |digit_str: &str| digit_str.parse::<i32>());
-    let neg_num = map_res(preceded(tag("-"), digit1), |digit_str: &str| {
-        digit_str.parse::<i32>().map(|i| -i)
-    });
-    alt((pos_num, neg_num)).parse(i)
+    num(Grammar::PERMISSIVE).parse(i)
 }
 
 #[test]
 fn test_num_tag() {
     assert_eq!(parse_num("666").unwrap().1, 666);
     assert_eq!(parse_num("-49").unwrap().1, -49);
+    assert!(parse_num("2147483648").is_err());
+    assert!(parse_num("-2147483648").is_err());
+}
+
+#[test]
+fn test_num_grammar() {
+    let mut puzzle = num(Grammar::PUZZLE);
+    assert_eq!(puzzle.parse("123").unwrap().1, 123);
+    assert!(puzzle.parse("0074").is_err());
+    assert!(puzzle.parse("1234").is_err());
+    assert!(puzzle.parse("-12").is_err());
+    assert!(puzzle.parse(" 12").is_err());
+
+    let mut spaced = num(Grammar {
+        whitespace: true,
+        ..Grammar::PERMISSIVE
+    });
+    assert_eq!(spaced.parse(" -1234 ,").unwrap(), (",", -1234));
+
+    // However many leading zeros there are, up to the longest operand.
+    let zeros = "0".repeat(MAX_OPERAND_LEN - 1);
+    assert_eq!(parse_num(&format!("{zeros}7")).unwrap().1, 7);
+    assert!(parse_num(&format!("{zeros}07")).is_err());
+    let padded = format!("{} 7", " ".repeat(MAX_OPERAND_LEN - 2));
+    let overlong = format!(" {padded}");
+    let mut spaced = num(Grammar {
+        whitespace: true,
+        ..Grammar::PERMISSIVE
+    });
+    assert_eq!(spaced.parse(padded.as_str()).unwrap().1, 7);
+    assert!(spaced.parse(overlong.as_str()).is_err());
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -36,17 +155,22 @@ pub enum Op {
     Dont,
 }
 
-fn parse_mul(i: &str) -> IResult<&str, Op, VerboseError<&str>> {
+fn mul<'a>(grammar: Grammar) -> impl Parser<&'a str, Output = Op, Error = VerboseError<&'a str>> {
     // A valid multiplication instruction is "mul", "(", Number, ",", Number, ")"
     let parser = preceded(
         tag("mul"),
         delimited(
             char('('),
-            separated_pair(parse_num, char(','), parse_num),
+            separated_pair(num(grammar), char(','), num(grammar)),
             char(')'),
         ),
     );
-    map(parser, |(a, b)| Op::Mul(a, b)).parse(i)
+    map(parser, |(a, b)| Op::Mul(a, b))
+}
+
+#[cfg(test)]
+fn parse_mul(i: &str) -> IResult<&str, Op, VerboseError<&str>> {
+    mul(Grammar::PERMISSIVE).parse(i)
 }
 
 #[test]
@@ -65,22 +189,39 @@ fn parse_dont(i: &str) -> IResult<&str, Op, VerboseError<&str>> {
     value(Op::Dont, tag("don't()")).parse(i)
 }
 
+#[test]
+fn test_mul_grammar() {
+    let sequence = "mul(1,2)mul( 3 , 4 )mul(-5,6)mul(1000,7)";
+    let spaced = Grammar {
+        whitespace: true,
+        ..Grammar::PUZZLE
+    };
+    let (_, ops) = parse_op_sequence_with(sequence, Grammar::PUZZLE).unwrap();
+    assert_eq!(ops, [Op::Mul(1, 2)]);
+    let (_, ops) = parse_op_sequence_with(sequence, spaced).unwrap();
+    assert_eq!(ops, [Op::Mul(1, 2), Op::Mul(3, 4)]);
+    let (_, ops) = parse_op_sequence_with(sequence, Grammar::PERMISSIVE).unwrap();
+    assert_eq!(ops, [Op::Mul(1, 2), Op::Mul(-5, 6), Op::Mul(1000, 7)]);
+}
+
 #[test]
 fn test_do_dont() {
     assert_eq!(parse_do("do()").unwrap().1, Op::Do);
     assert_eq!(parse_dont("don't()").unwrap().1, Op::Dont);
 }
 
-/// Parses all the valid [IMultiplication] instructions in the input sequence `i`, discarding the rest.
-fn parse_op_sequence(i: &str) -> IResult<&str, Vec<Op>, VerboseError<&str>> {
-    let mut ops = Vec::new();
+/// Runs `parser` at every position of `i`, collecting everything it recognises and discarding the rest.
+fn scan<'a, O>(
+    i: &'a str,
+    mut parser: impl Parser<&'a str, Output = O, Error = VerboseError<&'a str>>,
+) -> IResult<&'a str, Vec<O>, VerboseError<&'a str>> {
+    let mut found = Vec::new();
     let mut outer_rem = i;
-    let mut parse_op = alt((parse_mul, parse_do, parse_dont));
     // This could probably be written using nom, but I failed (see below for failure mode).
     while !outer_rem.is_empty() {
-        if let Ok((rem, op)) = parse_op.parse(outer_rem) {
+        if let Ok((rem, o)) = parser.parse(outer_rem) {
             outer_rem = rem;
-            ops.push(op);
+            found.push(o);
         } else {
             // Snip off a character.
             let (rem, _) = take(1u8).parse(outer_rem)?;
@@ -94,25 +235,111 @@ fn parse_op_sequence(i: &str) -> IResult<&str, Vec<Op>, VerboseError<&str>> {
     // )))
     // .parse(i)?;
     // let (remainder, muls) = res;
-    Ok((outer_rem, ops))
+    Ok((outer_rem, found))
 }
 
-/// Sums all muls, obeying any do or don't instructions.
-fn sum_ops(ops: &[Op]) -> i32 {
-    let mut sum = 0;
-    let mut op_do = true;
-    for op in ops {
-        match op {
-            Op::Mul(a, b) => {
-                if op_do {
-                    sum += a * b;
+/// Parses all the valid [Op] instructions in the input sequence `i`, discarding the rest.
+#[cfg(test)]
+fn parse_op_sequence(i: &str) -> IResult<&str, Vec<Op>, VerboseError<&str>> {
+    parse_op_sequence_with(i, Grammar::PERMISSIVE)
+}
+
+/// Parses all the valid [Op] instructions in `i`, reading `mul` operands according to `grammar`,
+/// and discarding the rest.
+fn parse_op_sequence_with(i: &str, grammar: Grammar) -> IResult<&str, Vec<Op>, VerboseError<&str>> {
+    scan(i, op(grammar))
+}
+
+/// Parses any one [Op], reading `mul` operands according to `grammar`.
+fn op<'a>(grammar: Grammar) -> impl Parser<&'a str, Output = Op, Error = VerboseError<&'a str>> {
+    alt((mul(grammar), parse_do, parse_dont))
+}
+
+/// Integer types ops can be summed in, with overflow checks.
+pub trait Accumulator: Copy + From<i32> {
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+}
+
+macro_rules! impl_accumulator {
+    ($($t:ty),*) => {
+        $(impl Accumulator for $t {
+            fn checked_add(self, other: Self) -> Option<Self> {
+                <$t>::checked_add(self, other)
+            }
+
+            fn checked_mul(self, other: Self) -> Option<Self> {
+                <$t>::checked_mul(self, other)
+            }
+        })*
+    };
+}
+
+impl_accumulator!(i32, i64, i128);
+
+/// The sum of ops no longer fits into the accumulator type.
+#[derive(Debug, PartialEq)]
+pub struct Overflow {
+    /// Index of the op that overflowed.
+    pub op: usize,
+}
+
+/// How [sum_ops] treats `do()` and `don't()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecMode {
+    /// Every mul counts, as in part 1.
+    IgnoreConditionals,
+    /// `don't()` disables muls until the next `do()`, as in part 2.
+    Conditionals,
+    /// Conditionals nest: every `don't()` needs its own `do()` before muls count again.
+    Scoped,
+}
+
+impl std::str::FromStr for ExecMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(ExecMode::IgnoreConditionals),
+            "conditionals" => Ok(ExecMode::Conditionals),
+            "scoped" => Ok(ExecMode::Scoped),
+            _ => Err(format!("unknown mode: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Evaluation<T> {
+    pub sum: T,
+    /// Indices of the muls that made it into the sum.
+    pub applied: Vec<usize>,
+}
+
+/// Sums all muls, treating any do or don't instructions according to `mode`.
+fn sum_ops<T: Accumulator>(ops: &[Op], mode: ExecMode) -> Result<Evaluation<T>, Overflow> {
+    let mut sum = T::from(0);
+    let mut applied = Vec::new();
+    // Number of `don't()`s not yet undone by a `do()`.
+    let mut disabled = 0usize;
+    for (idx, op) in ops.iter().enumerate() {
+        match (op, mode) {
+            (Op::Mul(a, b), _) => {
+                if disabled == 0 {
+                    sum = T::from(*a)
+                        .checked_mul(T::from(*b))
+                        .and_then(|product| sum.checked_add(product))
+                        .ok_or(Overflow { op: idx })?;
+                    applied.push(idx);
                 }
             }
-            Op::Do => op_do = true,
-            Op::Dont => op_do = false,
+            (_, ExecMode::IgnoreConditionals) => (),
+            (Op::Do, ExecMode::Conditionals) => disabled = 0,
+            (Op::Dont, ExecMode::Conditionals) => disabled = 1,
+            (Op::Do, ExecMode::Scoped) => disabled = disabled.saturating_sub(1),
+            (Op::Dont, ExecMode::Scoped) => disabled += 1,
         }
     }
-    sum
+    Ok(Evaluation { sum, applied })
 }
 
 #[test]
@@ -123,7 +350,8 @@ fn test_parser() {
         parsed_muls,
         [Op::Mul(2, 4), Op::Mul(5, 5), Op::Mul(11, 8), Op::Mul(8, 5)]
     );
-    assert_eq!(sum_ops(&parsed_muls), 161);
+    let evaluation = sum_ops::<i32>(&parsed_muls, ExecMode::Conditionals).unwrap();
+    assert_eq!(evaluation.sum, 161);
 }
 
 #[test]
@@ -141,13 +369,1237 @@ fn test_parser_2() {
             Op::Mul(8, 5)
         ]
     );
-    assert_eq!(sum_ops(&parsed_ops), 48);
+    let evaluation = sum_ops::<i32>(&parsed_ops, ExecMode::Conditionals).unwrap();
+    assert_eq!(evaluation.sum, 48);
+    assert_eq!(evaluation.applied, [0, 5]);
+}
+
+#[test]
+fn test_exec_modes() {
+    let sequence = "mul(1,1)don't()mul(1,2)don't()mul(1,4)do()mul(1,8)do()mul(1,16)do()mul(1,32)";
+    let (_, ops) = parse_op_sequence(sequence).unwrap();
+    let eval = |mode| sum_ops::<i32>(&ops, mode).unwrap();
+    assert_eq!(
+        eval(ExecMode::IgnoreConditionals),
+        Evaluation {
+            sum: 63,
+            applied: vec![0, 2, 4, 6, 8, 10]
+        }
+    );
+    assert_eq!(
+        eval(ExecMode::Conditionals),
+        Evaluation {
+            sum: 57,
+            applied: vec![0, 6, 8, 10]
+        }
+    );
+    assert_eq!(
+        eval(ExecMode::Scoped),
+        Evaluation {
+            sum: 49,
+            applied: vec![0, 8, 10]
+        }
+    );
+}
+
+#[test]
+fn test_sum_overflow() {
+    let ops = [
+        Op::Mul(2, 3),
+        Op::Mul(i32::MAX, 2),
+        Op::Mul(i32::MIN, i32::MAX),
+    ];
+    let mode = ExecMode::IgnoreConditionals;
+    assert_eq!(sum_ops::<i32>(&ops, mode), Err(Overflow { op: 1 }));
+    let wide = 6 + i32::MAX as i64 * 2 + i32::MIN as i64 * i32::MAX as i64;
+    assert_eq!(sum_ops::<i64>(&ops, mode).map(|e| e.sum), Ok(wide));
+    assert_eq!(sum_ops::<i128>(&ops, mode).map(|e| e.sum), Ok(wide as i128));
+    let many = vec![Op::Mul(i32::MIN, i32::MIN); 3];
+    assert_eq!(sum_ops::<i64>(&many, mode), Err(Overflow { op: 1 }));
+    let wide = 3 * (i32::MIN as i128).pow(2);
+    assert_eq!(sum_ops::<i128>(&many, mode).map(|e| e.sum), Ok(wide));
+}
+
+// Fast scanner.
+// Trying the whole `alt` of nom parsers at every offset is slow on big dumps,
+// so this finds the instructions by hand instead, jumping between possible starts with memchr.
+
+/// Which implementation finds the [Op]s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scanner {
+    /// [parse_op_sequence_with].
+    Nom,
+    /// [scan_ops_fast].
+    Fast,
+}
+
+impl std::str::FromStr for Scanner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nom" => Ok(Scanner::Nom),
+            "fast" => Ok(Scanner::Fast),
+            _ => Err(format!("unknown scanner: {s}")),
+        }
+    }
+}
+
+impl Scanner {
+    pub fn scan(self, i: &str, grammar: Grammar) -> Vec<Op> {
+        match self {
+            Scanner::Nom => parse_op_sequence_with(i, grammar).unwrap().1,
+            Scanner::Fast => scan_ops_fast(i, grammar),
+        }
+    }
+}
+
+/// Reads a number the way [num] does for `grammar`,
+/// returning it with the position right after it.
+fn scan_num(bytes: &[u8], from: usize, grammar: Grammar) -> Option<(i32, usize)> {
+    // Same whitespace as nom's `multispace0`.
+    let skip_space = |pos: usize| {
+        if grammar.whitespace {
+            pos + bytes[pos..]
+                .iter()
+                .take_while(|b| matches!(b, b' ' | b'\t' | b'\r' | b'\n'))
+                .count()
+        } else {
+            pos
+        }
+    };
+    let at = skip_space(from);
+    let (negative, start) = match bytes.get(at) {
+        Some(b'-') if grammar.signed => (true, at + 1),
+        _ => (false, at),
+    };
+    let digits = bytes[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if digits == 0 || grammar.max_digits.is_some_and(|max| digits > max) {
+        return None;
+    }
+    let end = start + digits;
+    // Digits are ASCII, so this is valid UTF-8.
+    let num: i32 = std::str::from_utf8(&bytes[start..end])
+        .unwrap()
+        .parse()
+        .ok()?;
+    let end = skip_space(end);
+    if end - from > MAX_OPERAND_LEN {
+        return None;
+    }
+    Some((if negative { -num } else { num }, end))
+}
+
+/// Reads `mul(a,b)` starting at `at`, returning it with the position right after it.
+fn scan_mul(bytes: &[u8], at: usize, grammar: Grammar) -> Option<(Op, usize)> {
+    let rest = &bytes[at..];
+    if !rest.starts_with(b"mul(") {
+        return None;
+    }
+    let (a, pos) = scan_num(bytes, at + 4, grammar)?;
+    if bytes.get(pos) != Some(&b',') {
+        return None;
+    }
+    let (b, pos) = scan_num(bytes, pos + 1, grammar)?;
+    if bytes.get(pos) != Some(&b')') {
+        return None;
+    }
+    Some((Op::Mul(a, b), pos + 1))
+}
+
+/// Finds the same [Op]s as [parse_op_sequence_with], in a single pass over the bytes.
+///
+/// Every instruction starts with `m` or `d`, which can't be part of a multi-byte character,
+/// so everything between those is skipped without looking at it.
+pub fn scan_ops_fast(i: &str, grammar: Grammar) -> Vec<Op> {
+    let bytes = i.as_bytes();
+    let mut ops = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = memchr::memchr2(b'm', b'd', &bytes[pos..]) {
+        let at = pos + found;
+        let rest = &bytes[at..];
+        let (op, next) = if rest.starts_with(b"do()") {
+            (Some(Op::Do), at + 4)
+        } else if rest.starts_with(b"don't()") {
+            (Some(Op::Dont), at + 7)
+        } else {
+            match scan_mul(bytes, at, grammar) {
+                Some((op, next)) => (Some(op), next),
+                None => (None, at + 1),
+            }
+        };
+        ops.extend(op);
+        pos = next;
+    }
+    ops
+}
+
+#[test]
+fn test_scan_ops_fast() {
+    let sequences = [
+        r#"xmul(2,4)%&mul[3,7]!@^do_not_mul(5,5)+mul(32,64]then(mul(11,8)mul(8,5))"#,
+        r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))"#,
+        "mul(2147483647,-2147483647)mul(2147483648,1)mul(-2147483648,1)mul(--1,2)mul(-,2)",
+        "ümul(1,2)€€don't()ŵmul(-3,4)do()mu",
+        "mul( 1 ,\n2\t)mul(0001,2)mul(123,- 4)mul(-0,1)mul(1 2,3)",
+    ];
+    let grammars = [
+        Grammar::PERMISSIVE,
+        Grammar::PUZZLE,
+        Grammar {
+            whitespace: true,
+            ..Grammar::PERMISSIVE
+        },
+        Grammar {
+            whitespace: true,
+            ..Grammar::PUZZLE
+        },
+    ];
+    for sequence in sequences {
+        for grammar in grammars {
+            assert_eq!(
+                Scanner::Fast.scan(sequence, grammar),
+                Scanner::Nom.scan(sequence, grammar),
+                "{grammar:?}: {sequence}"
+            );
+        }
+    }
+
+    // Random soup of instruction bits, with a fixed seed.
+    let alphabet: Vec<char> = "mul(),-0123456789don't[] \nü".chars().collect();
+    let mut seed = 0x2024u64;
+    for round in 0..2000 {
+        let grammar = grammars[round % grammars.len()];
+        let sequence: String = (0..64)
+            .map(|_| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                alphabet[(seed >> 33) as usize % alphabet.len()]
+            })
+            .collect();
+        assert_eq!(
+            Scanner::Fast.scan(&sequence, grammar),
+            Scanner::Nom.scan(&sequence, grammar),
+            "{grammar:?}: {sequence}"
+        );
+    }
+}
+
+// Clean programs.
+// Once the ops are dug out of the garbage, they can be stored as a clean program
+// with one instruction per line, which reads back into exactly the same ops.
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Mul(a, b) => write!(f, "mul({a},{b})"),
+            Op::Do => write!(f, "do()"),
+            Op::Dont => write!(f, "don't()"),
+        }
+    }
+}
+
+/// Writes `ops` as a clean program, an op per line.
+fn emit_program<'a>(ops: impl IntoIterator<Item = &'a Op>, out: &mut impl Write) -> io::Result<()> {
+    for op in ops {
+        writeln!(out, "{op}")?;
+    }
+    Ok(())
+}
+
+/// Line of a clean program which isn't exactly one op.
+#[derive(Debug, PartialEq)]
+pub struct CleanSyntaxError {
+    pub line: usize,
+}
+
+/// Reads a program written by [emit_program] back into its ops.
+///
+/// Unlike [parse_op_sequence_with], nothing is skipped: every line has to be exactly one op.
+fn parse_clean_program(i: &str) -> Result<Vec<Op>, CleanSyntaxError> {
+    i.lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            all_consuming(op(Grammar::PERMISSIVE))
+                .parse_complete(line)
+                .map(|(_, op)| op)
+                .map_err(|_| CleanSyntaxError { line: idx + 1 })
+        })
+        .collect()
+}
+
+#[test]
+fn test_clean_program() {
+    let sequence = r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(-32,64](mul(11,8)undo()?mul(8,-5))"#;
+    let (_, ops) = parse_op_sequence(sequence).unwrap();
+    let mut clean = Vec::new();
+    emit_program(&ops, &mut clean).unwrap();
+    let clean = String::from_utf8(clean).unwrap();
+    assert_eq!(
+        clean,
+        "mul(2,4)\ndon't()\nmul(5,5)\nmul(11,8)\ndo()\nmul(8,-5)\n"
+    );
+    assert_eq!(parse_clean_program(&clean), Ok(ops));
+    assert_eq!(parse_clean_program(""), Ok(vec![]));
+    assert_eq!(
+        parse_clean_program("mul(1,2)\nxmul(3,4)"),
+        Err(CleanSyntaxError { line: 2 })
+    );
+    assert_eq!(
+        parse_clean_program("do()\nmul(1,2)do()"),
+        Err(CleanSyntaxError { line: 2 })
+    );
+}
+
+// Auditing.
+// Keeps track of where every op came from, and why the garbage around them didn't parse.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedOp {
+    pub op: Op,
+    /// Byte range of the op in the input.
+    pub span: Range<usize>,
+}
+
+/// A spot in the garbage that starts like an instruction, but isn't one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NearMiss {
+    /// From the start of the instruction up to and including the character that broke it.
+    pub span: Range<usize>,
+    pub reason: String,
+}
+
+/// A run of garbage between valid ops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corruption {
+    pub span: Range<usize>,
+    pub near_misses: Vec<NearMiss>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audit {
+    pub ops: Vec<SpannedOp>,
+    pub corruptions: Vec<Corruption>,
+}
+
+/// Explains why the instruction that `i[at..]` starts like fails to parse, if it starts like one.
+fn near_miss(i: &str, at: usize, grammar: Grammar) -> Option<NearMiss> {
+    let rest = &i[at..];
+    let (name, parsed) = if rest.starts_with("mul") {
+        ("mul", mul(grammar).parse(rest))
+    } else if rest.starts_with("don") {
+        ("don't()", parse_dont(rest))
+    } else if rest.starts_with("do") {
+        ("do()", parse_do(rest))
+    } else {
+        return None;
+    };
+    let (failed_at, kind) = match parsed {
+        Ok(_) => return None,
+        Err(nom::Err::Incomplete(_)) => {
+            return Some(NearMiss {
+                span: at..i.len(),
+                reason: "input ends mid-instruction".to_string(),
+            })
+        }
+        // The first error is the deepest one.
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => e.errors[0].clone(),
+    };
+    let mut pos = i.len() - failed_at.len();
+    // Only `mul` gets past its name, to the numbers.
+    let bad_name = pos == at;
+    if bad_name {
+        // Point at where the name stops matching.
+        let matched = rest
+            .char_indices()
+            .zip(name.chars())
+            .find(|((_, c), expected)| c != expected)
+            .map_or(rest.len(), |((idx, _), _)| idx);
+        pos = at + matched;
+    }
+    let found = i[pos..].chars().next();
+    let found_text = match found {
+        Some(c) => format!("`{c}`"),
+        None => "end of input".to_string(),
+    };
+    let reason = match kind {
+        VerboseErrorKind::Char(c) => format!("expected `{c}`, found {found_text}"),
+        _ if bad_name => format!("expected `{name}`, found {found_text}"),
+        VerboseErrorKind::Nom(ErrorKind::MapRes | ErrorKind::TooLarge) => {
+            "number out of range".to_string()
+        }
+        _ => format!("expected a number, found {found_text}"),
+    };
+    let end = pos + found.map_or(0, char::len_utf8);
+    Some(NearMiss {
+        span: at..end,
+        reason,
+    })
+}
+
+/// Parses all the valid [Op] instructions in `i` like [parse_op_sequence_with],
+/// but also records where they are and what the garbage between them looks like.
+pub fn audit(i: &str, grammar: Grammar) -> Audit {
+    let mut parse_op = op(grammar);
+    let mut ops = Vec::new();
+    let mut corruptions = Vec::new();
+    let mut garbage: Option<Corruption> = None;
+    let mut pos = 0;
+    while pos < i.len() {
+        let rest = &i[pos..];
+        if let Ok((rem, op)) = parse_op.parse(rest) {
+            corruptions.extend(garbage.take());
+            let end = i.len() - rem.len();
+            ops.push(SpannedOp { op, span: pos..end });
+            pos = end;
+        } else {
+            let corruption = garbage.get_or_insert(Corruption {
+                span: pos..pos,
+                near_misses: Vec::new(),
+            });
+            corruption.near_misses.extend(near_miss(i, pos, grammar));
+            pos += rest.chars().next().unwrap().len_utf8();
+            corruption.span.end = pos;
+        }
+    }
+    corruptions.extend(garbage);
+    // Near misses can run into the op that follows them, keep them within their garbage.
+    for corruption in &mut corruptions {
+        for miss in &mut corruption.near_misses {
+            miss.span.end = miss.span.end.min(corruption.span.end);
+        }
+    }
+    Audit { ops, corruptions }
+}
+
+#[test]
+fn test_audit() {
+    let sequence = r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))"#;
+    let report = audit(sequence, Grammar::PERMISSIVE);
+    let (_, ops) = parse_op_sequence(sequence).unwrap();
+    let audited: Vec<Op> = report
+        .ops
+        .iter()
+        .map(|spanned| spanned.op.clone())
+        .collect();
+    assert_eq!(audited, ops);
+    for spanned in &report.ops {
+        let (_, op) = parse_op_sequence(&sequence[spanned.span.clone()]).unwrap();
+        assert_eq!(op, std::slice::from_ref(&spanned.op));
+    }
+    assert_eq!(report.ops[1].span, 20..27);
+
+    let garbage: Vec<&str> = report
+        .corruptions
+        .iter()
+        .map(|corruption| &sequence[corruption.span.clone()])
+        .collect();
+    assert_eq!(
+        garbage,
+        ["x", "&mul[3,7]!^", "_", "+mul(32,64](", "un", "?", ")"]
+    );
+    let misses: Vec<(&str, &str)> = report
+        .corruptions
+        .iter()
+        .flat_map(|corruption| &corruption.near_misses)
+        .map(|miss| (&sequence[miss.span.clone()], miss.reason.as_str()))
+        .collect();
+    assert_eq!(
+        misses,
+        [
+            ("mul[", "expected `(`, found `[`"),
+            ("mul(32,64]", "expected `)`, found `]`"),
+        ]
+    );
+
+    let reasons = |i, grammar| -> Vec<String> {
+        audit(i, grammar)
+            .corruptions
+            .into_iter()
+            .flat_map(|corruption| corruption.near_misses)
+            .map(|miss| miss.reason)
+            .collect()
+    };
+    assert_eq!(
+        reasons(
+            "mul(a,1)mul(99999999999,2)do_mul(4*don't(xdon'",
+            Grammar::PERMISSIVE
+        ),
+        [
+            "expected a number, found `a`",
+            "number out of range",
+            "expected `do()`, found `_`",
+            "expected `,`, found `*`",
+            "expected `don't()`, found `x`",
+            "input ends mid-instruction",
+        ]
+    );
+
+    // The puzzle's grammar turns away operands the permissive one takes.
+    let sequence = "mul(1234,5)mul(-1,2)mul(1,2)";
+    let report = audit(sequence, Grammar::PUZZLE);
+    assert_eq!(report.ops.len(), 1);
+    assert_eq!(report.ops[0].op, Op::Mul(1, 2));
+    assert_eq!(
+        reasons(sequence, Grammar::PUZZLE),
+        ["number out of range", "expected a number, found `-`"]
+    );
+    assert!(reasons(sequence, Grammar::PERMISSIVE).is_empty());
+}
+
+// Extended language.
+// Same corrupted memory, but with more instructions, named registers and output,
+// run by a small interpreter instead of `sum_ops`.
+
+/// Arithmetic instructions of the extended language.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Mul,
+    Add,
+    Sub,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Num(i32),
+    /// Named register, `acc` being the accumulator.
+    Reg(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    /// Arithmetic on two operands, with the result added to the accumulator, just like `mul` in the puzzle.
+    Bin(BinOp, Operand, Operand),
+    /// Stores an operand into a register.
+    Set(String, Operand),
+    /// Outputs an operand.
+    Print(Operand),
+    Do,
+    Dont,
+}
+
+impl From<Op> for Instr {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Mul(a, b) => Instr::Bin(BinOp::Mul, Operand::Num(a), Operand::Num(b)),
+            Op::Do => Instr::Do,
+            Op::Dont => Instr::Dont,
+        }
+    }
+}
+
+fn parse_reg(i: &str) -> IResult<&str, String, VerboseError<&str>> {
+    map(take_while1(|c: char| c.is_ascii_lowercase()), String::from).parse(i)
+}
+
+fn parse_operand(i: &str) -> IResult<&str, Operand, VerboseError<&str>> {
+    alt((map(parse_num, Operand::Num), map(parse_reg, Operand::Reg))).parse(i)
+}
+
+/// Which instructions a program is made of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// Only `mul` with numbers, `do()` and `don't()`, as in the puzzle.
+    Puzzle,
+    /// Everything in [Instr].
+    Extended,
+}
+
+impl std::str::FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "puzzle" => Ok(Dialect::Puzzle),
+            "extended" => Ok(Dialect::Extended),
+            _ => Err(format!("unknown dialect: {s}")),
+        }
+    }
+}
+
+impl Dialect {
+    /// Registry with the instructions of this dialect, which is what defines them.
+    pub fn registry(self) -> Registry {
+        match self {
+            Dialect::Puzzle => Registry::from_config(PUZZLE_CONFIG).unwrap(),
+            Dialect::Extended => Registry::from_config(EXTENDED_CONFIG).unwrap(),
+        }
+    }
+}
+
+/// Parses all the valid instructions of `dialect` in `i`, discarding the rest.
+fn parse_program(i: &str, dialect: Dialect) -> IResult<&str, Vec<Instr>, VerboseError<&str>> {
+    dialect.registry().parse(i)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    DivisionByZero,
+    Overflow,
+}
+
+/// Accumulator register, summing the results of all arithmetic instructions.
+const ACC: &str = "acc";
+
+/// Interpreter state for running programs.
+#[derive(Debug)]
+pub struct Vm {
+    /// Registers default to 0 until set.
+    registers: HashMap<String, i64>,
+    /// Cleared by `don't()`, set by `do()`. Everything else is skipped while disabled.
+    enabled: bool,
+    /// Values printed so far.
+    output: Vec<i64>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm {
+            registers: HashMap::new(),
+            enabled: true,
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Vm {
+    pub fn acc(&self) -> i64 {
+        self.registers.get(ACC).copied().unwrap_or(0)
+    }
+
+    fn read(&self, operand: &Operand) -> i64 {
+        match operand {
+            Operand::Num(n) => *n as i64,
+            Operand::Reg(reg) => self.registers.get(reg).copied().unwrap_or(0),
+        }
+    }
+
+    fn apply(&self, op: BinOp, a: &Operand, b: &Operand) -> Result<i64, VmError> {
+        let (a, b) = (self.read(a), self.read(b));
+        let result = match op {
+            BinOp::Mul => a.checked_mul(b),
+            BinOp::Add => a.checked_add(b),
+            BinOp::Sub => a.checked_sub(b),
+            BinOp::Div | BinOp::Mod if b == 0 => return Err(VmError::DivisionByZero),
+            BinOp::Div => a.checked_div(b),
+            BinOp::Mod => a.checked_rem(b),
+        };
+        result.ok_or(VmError::Overflow)
+    }
+
+    pub fn step(&mut self, instr: &Instr) -> Result<(), VmError> {
+        match instr {
+            Instr::Do => self.enabled = true,
+            Instr::Dont => self.enabled = false,
+            _ if !self.enabled => (),
+            Instr::Bin(op, a, b) => {
+                let result = self.apply(*op, a, b)?;
+                let acc = self.acc().checked_add(result).ok_or(VmError::Overflow)?;
+                self.registers.insert(ACC.to_string(), acc);
+            }
+            Instr::Set(reg, x) => {
+                let x = self.read(x);
+                self.registers.insert(reg.clone(), x);
+            }
+            Instr::Print(x) => self.output.push(self.read(x)),
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, program: &[Instr]) -> Result<(), VmError> {
+        for instr in program {
+            self.step(instr)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_vm_puzzle_compat() {
+    let sequence = r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))"#;
+    let (_, program) = parse_program(sequence, Dialect::Puzzle).unwrap();
+    let mut vm = Vm::default();
+    vm.run(&program).unwrap();
+    assert_eq!(vm.acc(), 48);
+}
+
+#[test]
+fn test_vm_extended() {
+    let sequence = r#"set(x,6)#add(x,4)~set[y,1]print(x)don't()print(acc)set(x,0)do()
+        div(x,4)mod(-7,x)print(acc)set(acc,0)print(acc)"#;
+    let (_, program) = parse_program(sequence, Dialect::Extended).unwrap();
+    let mut vm = Vm::default();
+    vm.run(&program).unwrap();
+    // 6 + 4, then 6 / 4 = 1 and -7 % 6 = -1, then a reset.
+    assert_eq!(vm.output, [6, 10, 0]);
+    assert_eq!(vm.acc(), 0);
+
+    let (_, program) = parse_program("set(z,0)mod(5,z)", Dialect::Extended).unwrap();
+    assert_eq!(Vm::default().run(&program), Err(VmError::DivisionByZero));
+}
+
+// Instruction registry.
+// Instructions of the extended language described as data, so new ones can be added
+// at runtime or from a config file, without touching the parsers above.
+
+/// Kind of argument a registered instruction takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Num,
+    Reg,
+    /// Number or register.
+    Operand,
+}
+
+/// What a registered instruction does, in terms of [Instr].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Semantics {
+    Bin(BinOp),
+    Set,
+    Print,
+    Do,
+    Dont,
+}
+
+impl Semantics {
+    /// Checks whether instructions with these semantics can take `args`.
+    fn accepts(self, args: &[ArgKind]) -> bool {
+        match self {
+            Semantics::Bin(_) => args.len() == 2,
+            Semantics::Set => args.len() == 2 && args[0] == ArgKind::Reg,
+            Semantics::Print => args.len() == 1,
+            Semantics::Do | Semantics::Dont => args.is_empty(),
+        }
+    }
+
+    /// Builds the instruction from arguments which [Semantics::accepts] has vetted.
+    fn build(self, mut args: Vec<Operand>) -> Instr {
+        match self {
+            Semantics::Bin(op) => {
+                let b = args.pop().unwrap();
+                let a = args.pop().unwrap();
+                Instr::Bin(op, a, b)
+            }
+            Semantics::Set => {
+                let x = args.pop().unwrap();
+                let Some(Operand::Reg(reg)) = args.pop() else {
+                    unreachable!("set takes a register first");
+                };
+                Instr::Set(reg, x)
+            }
+            Semantics::Print => Instr::Print(args.pop().unwrap()),
+            Semantics::Do => Instr::Do,
+            Semantics::Dont => Instr::Dont,
+        }
+    }
+}
+
+/// A registered instruction, looking like `name(arg,arg,...)` in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstrKind {
+    pub name: String,
+    pub args: Vec<ArgKind>,
+    pub semantics: Semantics,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegistryError {
+    /// Config line which couldn't be read.
+    Syntax { line: usize },
+    /// Instruction whose arguments don't fit its semantics.
+    Arguments { name: String },
+    /// Instruction with the same name as an earlier one.
+    Duplicate { name: String },
+}
+
+/// Instructions the registry scanner recognises.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    kinds: Vec<InstrKind>,
+}
+
+/// [Registry] config with the instructions of [Dialect::Puzzle].
+const PUZZLE_CONFIG: &str = r#"mul(num,num) = mul
+do() = do
+don't() = dont"#;
+
+/// [Registry] config with the instructions of [Dialect::Extended].
+const EXTENDED_CONFIG: &str = r#"mul(operand,operand) = mul
+add(operand,operand) = add
+sub(operand,operand) = sub
+div(operand,operand) = div
+mod(operand,operand) = mod
+set(reg,operand) = set
+print(operand) = print
+do() = do
+don't() = dont"#;
+
+fn parse_arg_kind(i: &str) -> IResult<&str, ArgKind, VerboseError<&str>> {
+    alt((
+        value(ArgKind::Num, tag("num")),
+        value(ArgKind::Reg, tag("reg")),
+        value(ArgKind::Operand, tag("operand")),
+    ))
+    .parse_complete(i)
+}
+
+fn parse_semantics(i: &str) -> IResult<&str, Semantics, VerboseError<&str>> {
+    alt((
+        value(Semantics::Bin(BinOp::Mul), tag("mul")),
+        value(Semantics::Bin(BinOp::Add), tag("add")),
+        value(Semantics::Bin(BinOp::Sub), tag("sub")),
+        value(Semantics::Bin(BinOp::Div), tag("div")),
+        value(Semantics::Bin(BinOp::Mod), tag("mod")),
+        value(Semantics::Set, tag("set")),
+        value(Semantics::Print, tag("print")),
+        value(Semantics::Dont, tag("dont")),
+        value(Semantics::Do, tag("do")),
+    ))
+    .parse_complete(i)
+}
+
+/// Parses a config line of the form `name(arg,arg,...) = semantics`.
+///
+/// Config parsers run in complete mode, since lines end where the config says they do.
+fn parse_instr_kind(i: &str) -> IResult<&str, InstrKind, VerboseError<&str>> {
+    let name = take_while1(|c: char| c != '(' && !c.is_whitespace());
+    let args = delimited(
+        char('('),
+        separated_list0(char(','), parse_arg_kind),
+        char(')'),
+    );
+    let semantics = preceded((space0, char('='), space0), parse_semantics);
+    map((name, args, semantics), |(name, args, semantics)| {
+        InstrKind {
+            name: name.to_string(),
+            args,
+            semantics,
+        }
+    })
+    .parse_complete(i)
+}
+
+impl Registry {
+    pub fn register(&mut self, kind: InstrKind) -> Result<(), RegistryError> {
+        if !kind.semantics.accepts(&kind.args) {
+            return Err(RegistryError::Arguments { name: kind.name });
+        }
+        if self.kinds.iter().any(|known| known.name == kind.name) {
+            return Err(RegistryError::Duplicate { name: kind.name });
+        }
+        self.kinds.push(kind);
+        Ok(())
+    }
+
+    /// Reads a registry from a config with an instruction per line, like [EXTENDED_CONFIG].
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn from_config(config: &str) -> Result<Registry, RegistryError> {
+        let mut registry = Registry::default();
+        for (idx, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (_, kind) = all_consuming(parse_instr_kind)
+                .parse_complete(line)
+                .map_err(|_| RegistryError::Syntax { line: idx + 1 })?;
+            registry.register(kind)?;
+        }
+        Ok(registry)
+    }
+
+    /// Parses the registered instruction `kind` at the start of `i`.
+    fn parse_kind<'a>(
+        kind: &InstrKind,
+        i: &'a str,
+    ) -> IResult<&'a str, Instr, VerboseError<&'a str>> {
+        let (mut i, _) = (tag(kind.name.as_str()), char('(')).parse(i)?;
+        let mut args = Vec::with_capacity(kind.args.len());
+        for (idx, arg) in kind.args.iter().enumerate() {
+            if idx > 0 {
+                (i, _) = char(',').parse(i)?;
+            }
+            let (rem, operand) = match arg {
+                ArgKind::Num => map(parse_num, Operand::Num).parse(i)?,
+                ArgKind::Reg => map(parse_reg, Operand::Reg).parse(i)?,
+                ArgKind::Operand => parse_operand(i)?,
+            };
+            i = rem;
+            args.push(operand);
+        }
+        let (i, _) = char(')').parse(i)?;
+        Ok((i, kind.semantics.build(args)))
+    }
+
+    /// Parses all the registered instructions in `i`, discarding the rest.
+    pub fn parse<'a>(&self, i: &'a str) -> IResult<&'a str, Vec<Instr>, VerboseError<&'a str>> {
+        scan(i, |i: &'a str| {
+            for kind in &self.kinds {
+                if let Ok(found) = Registry::parse_kind(kind, i) {
+                    return Ok(found);
+                }
+            }
+            Err(nom::Err::Error(VerboseError::from_error_kind(
+                i,
+                ErrorKind::Alt,
+            )))
+        })
+    }
+}
+
+#[test]
+fn test_registry_dialects() {
+    let parse = |i| parse_program(i, Dialect::Extended).unwrap().1;
+    assert_eq!(
+        parse("sub(acc,-3)"),
+        [Instr::Bin(
+            BinOp::Sub,
+            Operand::Reg("acc".into()),
+            Operand::Num(-3)
+        )]
+    );
+    assert_eq!(parse("set(x,7)"), [Instr::Set("x".into(), Operand::Num(7))]);
+    assert_eq!(parse("print(x)"), [Instr::Print(Operand::Reg("x".into()))]);
+    assert!(parse("set(7,x)").is_empty());
+
+    // The puzzle dialect finds the same instructions as the op parsers.
+    let sequence = r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(-32,64](mul(x,8)undo()?mul(8,5))"#;
+    let (_, ops) = parse_op_sequence(sequence).unwrap();
+    let (_, program) = parse_program(sequence, Dialect::Puzzle).unwrap();
+    assert_eq!(
+        program,
+        ops.into_iter().map(Instr::from).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_registry_custom() {
+    let config = r#"
+        # Puzzle instructions under new names, and a print for shouting.
+        times(num,num) = mul
+        shout(operand) = print
+        keep(reg,num) = set
+        please() = do
+        stop() = dont
+    "#;
+    let registry = Registry::from_config(config).unwrap();
+    let sequence =
+        "times(2,3)mul(4,5)keep(r,7)stop()times(9,9)please()shout(r)times(x,1)shout(acc)";
+    let (_, program) = registry.parse(sequence).unwrap();
+    let mut vm = Vm::default();
+    vm.run(&program).unwrap();
+    assert_eq!(vm.output, [7, 6]);
+
+    let error = |config| Registry::from_config(config).unwrap_err();
+    assert_eq!(
+        error("oops(num) = set"),
+        RegistryError::Arguments {
+            name: "oops".into()
+        }
+    );
+    assert_eq!(
+        error("a(num) = print\na(reg) = print"),
+        RegistryError::Duplicate { name: "a".into() }
+    );
+    assert_eq!(
+        error("a(num) = print\n\nb(num = print"),
+        RegistryError::Syntax { line: 3 }
+    );
+}
+
+// Streaming.
+// Dumps can be far bigger than memory, so ops are also parsed from a reader, a buffer at a time.
+
+/// Longest an op can be, so [OpStream] never waits for more than this to complete one.
+const MAX_OP_LEN: usize = "mul(,)".len() + 2 * MAX_OPERAND_LEN;
+
+/// Iterator over the [Op]s in a reader, holding at most a buffer and [MAX_OP_LEN] bytes of input in memory.
+pub struct OpStream<R> {
+    reader: R,
+    /// Decoded input which hasn't been dropped yet.
+    pending: String,
+    /// Bytes of a UTF-8 character split across reads.
+    partial: Vec<u8>,
+    /// Position in `pending` up to which everything has been parsed.
+    pos: usize,
+    /// Position of the first `)` at or after `pos`, if there is one in `pending`.
+    close: Option<usize>,
+    /// Position up to which `pending` has been searched for `)`.
+    searched: usize,
+    eof: bool,
+    grammar: Grammar,
+}
+
+impl<R: BufRead> OpStream<R> {
+    pub fn new(reader: R) -> Self {
+        OpStream {
+            reader,
+            pending: String::new(),
+            partial: Vec::new(),
+            pos: 0,
+            close: None,
+            searched: 0,
+            eof: false,
+            grammar: Grammar::PERMISSIVE,
+        }
+    }
+
+    /// Reads `mul` operands according to `grammar`, instead of permissively.
+    pub fn with_grammar(mut self, grammar: Grammar) -> Self {
+        self.grammar = grammar;
+        self
+    }
+
+    /// Checks whether parsing at `pos` can't change with more input.
+    ///
+    /// Every op ends with the first `)` after its start,
+    /// so once there's a `)` in the buffer, the parser never looks past it.
+    fn decidable(&mut self) -> bool {
+        if self.close.is_some_and(|close| close < self.pos) {
+            self.close = None;
+            self.searched = self.pos;
+        }
+        if self.close.is_none() {
+            let from = self.searched.max(self.pos);
+            self.close = self.pending[from..].find(')').map(|idx| from + idx);
+            self.searched = self.pending.len();
+        }
+        self.eof || self.close.is_some() || self.pending.len() - self.pos >= MAX_OP_LEN
+    }
+
+    /// Drops the parsed input and reads the next buffer.
+    fn fill(&mut self) -> io::Result<()> {
+        self.pending.drain(..self.pos);
+        self.close = self.close.map(|close| close - self.pos);
+        self.searched -= self.pos;
+        self.pos = 0;
+
+        let buf = self.reader.fill_buf()?;
+        if buf.is_empty() {
+            self.eof = true;
+            if !self.partial.is_empty() {
+                self.pending.push(char::REPLACEMENT_CHARACTER);
+            }
+            return Ok(());
+        }
+        let len = buf.len();
+        self.partial.extend_from_slice(buf);
+        self.reader.consume(len);
+
+        // Decode what we can, keeping a trailing incomplete character for the next read.
+        // Invalid bytes are garbage either way, so they're replaced.
+        let mut start = 0;
+        loop {
+            match std::str::from_utf8(&self.partial[start..]) {
+                Ok(valid) => {
+                    self.pending.push_str(valid);
+                    self.partial.clear();
+                    return Ok(());
+                }
+                Err(e) => {
+                    let valid = start + e.valid_up_to();
+                    self.pending
+                        .push_str(std::str::from_utf8(&self.partial[start..valid]).unwrap());
+                    match e.error_len() {
+                        Some(invalid) => {
+                            self.pending.push(char::REPLACEMENT_CHARACTER);
+                            start = valid + invalid;
+                        }
+                        None => {
+                            self.partial.drain(..valid);
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for OpStream<R> {
+    type Item = io::Result<Op>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while !self.decidable() {
+                if let Err(e) = self.fill() {
+                    return Some(Err(e));
+                }
+            }
+            let rest = &self.pending[self.pos..];
+            let first = rest.chars().next()?;
+            if let Ok((rem, op)) = op(self.grammar).parse(rest) {
+                self.pos = self.pending.len() - rem.len();
+                return Some(Ok(op));
+            }
+            // Snip off a character.
+            self.pos += first.len_utf8();
+        }
+    }
+}
+
+#[test]
+fn test_op_stream() {
+    let sequences = [
+        r#"xmul(2,4)%&mul[3,7]!@^do_not_mul(5,5)+mul(32,64]then(mul(11,8)mul(8,5))"#,
+        r#"xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))"#,
+        "ümul(1,2)€€don't()ŵmul(-3,4)do()mu",
+        "mul( 1 ,\n2\t)mul(1234,5)mul(12 ,3)",
+    ];
+    let spaced = Grammar {
+        whitespace: true,
+        ..Grammar::PUZZLE
+    };
+    for sequence in sequences {
+        for grammar in [Grammar::PERMISSIVE, Grammar::PUZZLE, spaced] {
+            let (_, expected) = parse_op_sequence_with(sequence, grammar).unwrap();
+            // Tiny buffers split both ops and multi-byte characters.
+            for capacity in [1, 2, 3, 7, 64] {
+                let reader = BufReader::with_capacity(capacity, sequence.as_bytes());
+                let ops: Vec<Op> = OpStream::new(reader)
+                    .with_grammar(grammar)
+                    .map(Result::unwrap)
+                    .collect();
+                assert_eq!(ops, expected, "capacity {capacity}: {sequence}");
+            }
+        }
+    }
+
+    // Every scanner reads padded operands up to the longest there can be, and no further.
+    let zeros = "0".repeat(MAX_OPERAND_LEN - 1);
+    let cases = [
+        (format!("mul({zeros}1,2)"), vec![Op::Mul(1, 2)]),
+        (format!("mul({zeros}01,2)mul(3,4)"), vec![Op::Mul(3, 4)]),
+        (format!("mul({}1,2)", "0".repeat(5000)), vec![]),
+    ];
+    for (sequence, expected) in cases {
+        let grammar = Grammar::PERMISSIVE;
+        assert_eq!(
+            parse_op_sequence_with(&sequence, grammar).unwrap().1,
+            expected
+        );
+        assert_eq!(scan_ops_fast(&sequence, grammar), expected);
+        let reader = BufReader::with_capacity(64, sequence.as_bytes());
+        let ops: Vec<Op> = OpStream::new(reader).map(Result::unwrap).collect();
+        assert_eq!(ops, expected);
+    }
 }
 
 fn main() {
-    let input = std::fs::read_to_string("day3/input.txt").unwrap();
-    let (_, ops) = parse_op_sequence(&input).unwrap();
-    // For part 1 result, you have to remove all Op::Do, Op::Dont from the parsed `ops`.
-    let sum = sum_ops(&ops);
-    println!("{sum}");
+    let path = "day3/input.txt";
+    // `--grammar=<puzzle|permissive>` reads the `mul` operands by those rules instead,
+    // with `--whitespace` allowing whitespace around them.
+    let grammar: Option<Grammar> = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--grammar=").map(|g| g.parse().unwrap()))
+        .map(|grammar| Grammar {
+            whitespace: std::env::args().any(|arg| arg == "--whitespace"),
+            ..grammar
+        });
+    // Clean programs and the interpreter have grammars of their own.
+    for ignores_grammar in ["--clean", "--vm=", "--registry="] {
+        if grammar.is_some() && std::env::args().any(|arg| arg.starts_with(ignores_grammar)) {
+            panic!("--grammar can't be used with {ignores_grammar}");
+        }
+    }
+    let grammar = grammar.unwrap_or(Grammar::PERMISSIVE);
+    // `--stream` runs the puzzle's ops straight from the file, without reading all of it in.
+    if std::env::args().any(|arg| arg == "--stream") {
+        let mut vm = Vm::default();
+        let reader = BufReader::new(File::open(path).unwrap());
+        for op in OpStream::new(reader).with_grammar(grammar) {
+            vm.step(&Instr::from(op.unwrap())).unwrap();
+        }
+        println!("{}", vm.acc());
+        return;
+    }
+    let input = std::fs::read_to_string(path).unwrap();
+    // `--bench` times both scanners on the input.
+    if std::env::args().any(|arg| arg == "--bench") {
+        for scanner in [Scanner::Nom, Scanner::Fast] {
+            let start = std::time::Instant::now();
+            let ops = scanner.scan(&input, grammar);
+            println!("{scanner:?}: {} ops in {:?}", ops.len(), start.elapsed());
+        }
+        return;
+    }
+    // `--audit` lists the garbage and everything in it that nearly was an instruction.
+    if std::env::args().any(|arg| arg == "--audit") {
+        let audit = audit(&input, grammar);
+        for corruption in &audit.corruptions {
+            for miss in &corruption.near_misses {
+                let text = &input[miss.span.clone()];
+                println!("{:?} {text:?}: {}", miss.span, miss.reason);
+            }
+        }
+        let garbage: usize = audit.corruptions.iter().map(|c| c.span.len()).sum();
+        println!(
+            "{} ops, {garbage} bytes of garbage in {} regions",
+            audit.ops.len(),
+            audit.corruptions.len()
+        );
+        return;
+    }
+    // `--vm=<dialect>` runs the input as a program in the interpreter instead.
+    let dialect: Option<Dialect> = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--vm=")
+            .map(|dialect| dialect.parse().unwrap())
+    });
+    // `--registry=<config>` runs it with the instructions from a registry config instead.
+    let registry = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--registry=")
+            .map(|path| Registry::from_config(&std::fs::read_to_string(path).unwrap()).unwrap())
+    });
+    if dialect.is_some() || registry.is_some() {
+        let (_, program) = match registry {
+            Some(registry) => registry.parse(&input).unwrap(),
+            None => parse_program(&input, dialect.unwrap()).unwrap(),
+        };
+        let mut vm = Vm::default();
+        vm.run(&program).unwrap();
+        for value in &vm.output {
+            println!("{value}");
+        }
+        println!("{}", vm.acc());
+        return;
+    }
+    // `--scanner=<nom|fast>` picks how the ops are found.
+    let scanner = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--scanner=").map(|s| s.parse().unwrap()))
+        .unwrap_or(Scanner::Nom);
+    // `--clean` reads the input as a clean program instead of corrupted memory.
+    let ops = if std::env::args().any(|arg| arg == "--clean") {
+        parse_clean_program(&input).unwrap()
+    } else {
+        scanner.scan(&input, grammar)
+    };
+    // `--emit` writes the ops out as a clean program.
+    if std::env::args().any(|arg| arg == "--emit") {
+        emit_program(&ops, &mut io::stdout().lock()).unwrap();
+        return;
+    }
+    // Part 1 ignores conditionals, part 2 obeys them.
+    // `--mode=<ignore|conditionals|scoped>` evaluates just that mode instead.
+    let mode: Option<ExecMode> = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--mode=")
+            .map(|mode| mode.parse().unwrap())
+    });
+    let modes = match mode {
+        Some(mode) => vec![mode],
+        None => vec![ExecMode::IgnoreConditionals, ExecMode::Conditionals],
+    };
+    for mode in modes {
+        match sum_ops::<i64>(&ops, mode) {
+            Ok(evaluation) => println!("{mode:?}: {}", evaluation.sum),
+            Err(Overflow { op }) => {
+                println!("{mode:?}: sum overflows i64 at op {op}, summing in i128 instead");
+                println!("{mode:?}: {}", sum_ops::<i128>(&ops, mode).unwrap().sum);
+            }
+        }
+    }
 }
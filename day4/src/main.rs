@@ -1,40 +1,111 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 /// Represent text as 2d massive,
 /// with line_len x lines dimensions.
+///
+/// Cells are Unicode scalar values, so a line's width is its `char` count.
 pub struct Text2D {
     chars: Vec<char>,
     line_len: i32,
     lines: i32,
+    addressing: Addressing,
+}
+
+/// What coordinates past the edges of a [Text2D] refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Addressing {
+    /// Nothing is past the edges.
+    #[default]
+    Bounded,
+    /// Coordinates wrap around to the opposite edge, as on a torus.
+    Toroidal,
+}
+
+/// A line whose width doesn't match the lines before it.
+#[derive(Debug, PartialEq)]
+pub struct RaggedLine {
+    /// 1-based line number.
+    pub line: usize,
+    pub width: usize,
+    pub expected: usize,
 }
 
 impl Text2D {
-    pub fn new(txt: &str) -> Self {
+    pub fn new(txt: &str) -> Result<Self, RaggedLine> {
         let mut chars = Vec::with_capacity(txt.len());
         let mut lines_count = 0usize;
-        let mut line_len = 0usize;
+        let mut line_len = None;
         let lines = txt.lines();
-        for line in lines {
-            line_len = line.len();
-            lines_count += 1;
-            for ch in line.chars() {
-                chars.push(ch);
+        for (idx, line) in lines.enumerate() {
+            let before = chars.len();
+            chars.extend(line.chars());
+            let width = chars.len() - before;
+            match line_len {
+                None => line_len = Some(width),
+                Some(expected) if expected != width => {
+                    return Err(RaggedLine {
+                        line: idx + 1,
+                        width,
+                        expected,
+                    })
+                }
+                Some(_) => {}
             }
+            lines_count += 1;
         }
-        Text2D {
+        Ok(Text2D {
             chars,
-            line_len: line_len as i32,
+            line_len: line_len.unwrap_or(0) as i32,
             lines: lines_count as i32,
+            addressing: Addressing::Bounded,
+        })
+    }
+
+    pub fn with_addressing(mut self, addressing: Addressing) -> Self {
+        self.addressing = addressing;
+        self
+    }
+
+    /// The cell that `(x, y)` refers to under the addressing mode, if any.
+    fn cell(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        if self.chars.is_empty() {
+            return None;
+        }
+        match self.addressing {
+            Addressing::Bounded => {
+                (x >= 0 && x < self.line_len && y >= 0 && y < self.lines).then_some((x, y))
+            }
+            Addressing::Toroidal => Some((x.rem_euclid(self.line_len), y.rem_euclid(self.lines))),
         }
     }
 
     pub fn at(&self, x: i32, y: i32) -> Option<char> {
-        if x >= 0 && x < self.line_len && y >= 0 && y < self.lines {
-            Some(self.chars[(x + y * self.line_len) as usize])
-        } else {
-            None
+        self.cell(x, y)
+            .map(|(x, y)| self.chars[(x + y * self.line_len) as usize])
+    }
+
+    /// How many cells a line running in `direction` crosses before it comes back around
+    /// to where it started. Lines never come back around in a bounded grid.
+    fn period(&self, direction: Direction) -> usize {
+        let (width, height) = (self.line_len as usize, self.lines as usize);
+        match (self.addressing, direction.delta()) {
+            (Addressing::Bounded, _) => usize::MAX,
+            (Addressing::Toroidal, (_, 0)) => width,
+            (Addressing::Toroidal, (0, _)) => height,
+            // Diagonals come back once both coordinates have wrapped a whole number of times.
+            (Addressing::Toroidal, _) => width / gcd(width, height) * height,
         }
     }
 }
 
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
 #[cfg(test)]
 const TEST_CASE: &str = r#"MMMSXXMASM
 MSAMXMSMSA
@@ -49,7 +120,7 @@ MXMXAXMASX"#;
 
 #[test]
 fn test_indexing() {
-    let text = Text2D::new(TEST_CASE);
+    let text = Text2D::new(TEST_CASE).unwrap();
     assert_eq!(text.at(0, 0), Some('M'));
     assert_eq!(text.at(1, 1), Some('S'));
     assert_eq!(text.at(9, 9), Some('X'));
@@ -58,122 +129,1046 @@ fn test_indexing() {
     assert_eq!(text.at(10, 9), None);
 }
 
-fn next_letter(cur: char, candidate: char) -> (bool, bool) {
-    let (valid, finished) = match cur {
-        'X' => (candidate == 'M', false),
-        'M' => (candidate == 'A', false),
-        // If next letter is S, we have XMAS
-        'A' => (false, candidate == 'S'),
-        _ => (false, false),
-    };
-    (valid, finished)
+#[test]
+fn test_ragged_and_unicode() {
+    // Widths count chars, not bytes.
+    let text = Text2D::new("ÅßC\nx€z").unwrap();
+    assert_eq!(text.at(1, 0), Some('ß'));
+    assert_eq!(text.at(2, 1), Some('z'));
+    assert_eq!(text.at(3, 0), None);
+    assert_eq!(
+        Text2D::new("abc\nabc\nab\nabcd").err(),
+        Some(RaggedLine {
+            line: 3,
+            width: 2,
+            expected: 3
+        })
+    );
+    assert_eq!(
+        Text2D::new("ab\nñ").err(),
+        Some(RaggedLine {
+            line: 2,
+            width: 1,
+            expected: 2
+        })
+    );
+    assert_eq!(Text2D::new("").unwrap().at(0, 0), None);
 }
 
-fn inner(x: i32, y: i32, dx: i32, dy: i32, txt: &Text2D) -> (i32, i32, bool, bool) {
-    let cur = txt.at(x, y).unwrap();
-    let tx = x + dx;
-    let ty = y + dy;
-    let Some(candidate) = txt.at(tx, ty) else {
-        return (tx, ty, false, false);
-    };
-    let (valid, finished) = next_letter(cur, candidate);
-    let victory = if finished { "XMAS" } else { "" };
-    println!("-- ({dx}, {dy}) {cur} -> {candidate}: ({x}, {y}) -> ({tx}, {ty}) {victory}");
-    (tx, ty, valid, finished)
+/// One of the 8 directions a word can run in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    N,
+    NE,
+    E,
+    SE,
+    S,
+    SW,
+    W,
+    NW,
 }
 
-fn xmas_loop(x: i32, y: i32, dx: i32, dy: i32, txt: &Text2D, count: &mut usize) {
-    let mut tx = x;
-    let mut ty = y;
-    let mut valid = true;
-    while valid {
-        let finished;
-        (tx, ty, valid, finished) = inner(tx, ty, dx, dy, txt);
-        if finished {
-            *count += 1;
+impl Direction {
+    pub const ALL: [Direction; 8] = [
+        Direction::N,
+        Direction::NE,
+        Direction::E,
+        Direction::SE,
+        Direction::S,
+        Direction::SW,
+        Direction::W,
+        Direction::NW,
+    ];
+
+    /// Step in this direction as (dx, dy), with y growing downwards.
+    pub fn delta(self) -> (i32, i32) {
+        match self {
+            Direction::N => (0, -1),
+            Direction::NE => (1, -1),
+            Direction::E => (1, 0),
+            Direction::SE => (1, 1),
+            Direction::S => (0, 1),
+            Direction::SW => (-1, 1),
+            Direction::W => (-1, 0),
+            Direction::NW => (-1, -1),
         }
     }
 }
 
-fn check_xmas(x: i32, y: i32, txt: &Text2D, count: &mut usize) {
-    let ch = txt.at(x, y).unwrap();
-    println!("({x}, {y}): {ch}");
-    if ch != 'X' {
-        return;
+/// A word found in a [Text2D].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordMatch {
+    pub start: (i32, i32),
+    pub direction: Direction,
+    /// Cells spelling out the word, from first letter to last.
+    pub cells: Vec<(i32, i32)>,
+}
+
+impl AsRef<[(i32, i32)]> for WordMatch {
+    fn as_ref(&self) -> &[(i32, i32)] {
+        &self.cells
     }
-    // -1, -1
-    xmas_loop(x, y, -1, -1, txt, count);
-    // -1, 0
-    xmas_loop(x, y, -1, 0, txt, count);
-    // -1, 1
-    xmas_loop(x, y, -1, 1, txt, count);
-    // 0, -1
-    xmas_loop(x, y, 0, -1, txt, count);
-    // 0, 1
-    xmas_loop(x, y, 0, 1, txt, count);
-    // 1, -1
-    xmas_loop(x, y, 1, -1, txt, count);
-    // 1, 0
-    xmas_loop(x, y, 1, 0, txt, count);
-    // 1, 1
-    xmas_loop(x, y, 1, 1, txt, count);
 }
 
-fn count_xmas(i: &str) -> usize {
-    let text = Text2D::new(i);
-    let mut count = 0;
-    for y in 0..text.lines {
-        for x in 0..text.line_len {
-            check_xmas(x, y, &text, &mut count);
+/// The same cells read either way round, so a palindrome found from both
+/// ends counts once.
+fn canonical_cells(cells: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    let forwards = cells.to_vec();
+    let mut reversed = forwards.clone();
+    reversed.reverse();
+    forwards.min(reversed)
+}
+
+impl Text2D {
+    /// Finds every occurrence of `word` running in any of `directions`.
+    ///
+    /// Matches may overlap. A palindrome reads the same in opposite directions,
+    /// so a run of cells is only reported once, from whichever end is found first.
+    /// With [Addressing::Toroidal], words continue across the edges, but never use
+    /// the same cell twice.
+    pub fn find_word(&self, word: &str, directions: &[Direction]) -> Vec<WordMatch> {
+        let letters: Vec<char> = word.chars().collect();
+        let mut matches = Vec::new();
+        if letters.is_empty() {
+            return matches;
+        }
+        let mut seen = HashSet::new();
+        for y in 0..self.lines {
+            for x in 0..self.line_len {
+                if self.at(x, y) != Some(letters[0]) {
+                    continue;
+                }
+                for &direction in directions {
+                    // A longer word would have to loop over its own cells.
+                    if letters.len() > self.period(direction) {
+                        continue;
+                    }
+                    let (dx, dy) = direction.delta();
+                    let Some(cells) = (0..letters.len() as i32)
+                        .map(|step| self.cell(x + dx * step, y + dy * step))
+                        .collect::<Option<Vec<(i32, i32)>>>()
+                    else {
+                        continue;
+                    };
+                    let spelled = cells
+                        .iter()
+                        .zip(&letters)
+                        .all(|(&(cx, cy), &letter)| self.at(cx, cy) == Some(letter));
+                    if !spelled {
+                        continue;
+                    }
+                    if !seen.insert(canonical_cells(&cells)) {
+                        continue;
+                    }
+                    matches.push(WordMatch {
+                        start: (x, y),
+                        direction,
+                        cells,
+                    });
+                }
+            }
         }
+        matches
     }
-    count
+}
+
+#[test]
+fn test_find_word() {
+    let text = Text2D::new(TEST_CASE).unwrap();
+    let matches = text.find_word("XMAS", &[Direction::E]);
+    assert_eq!(matches.len(), 3);
+    assert_eq!(
+        matches[0],
+        WordMatch {
+            start: (5, 0),
+            direction: Direction::E,
+            cells: vec![(5, 0), (6, 0), (7, 0), (8, 0)],
+        }
+    );
+    let backwards_or_up = text.find_word("XMAS", &[Direction::W, Direction::N]);
+    assert_eq!(backwards_or_up.len(), 2 + 2);
+    assert!(text.find_word("XMAZ", &Direction::ALL).is_empty());
+    assert!(text.find_word("", &Direction::ALL).is_empty());
+
+    // Palindromes count once per run of cells, while overlapping runs all count.
+    let text = Text2D::new("ABABA\nXXXXX").unwrap();
+    assert_eq!(text.find_word("ABA", &Direction::ALL).len(), 2);
+    assert_eq!(text.find_word("ABA", &[Direction::W]).len(), 2);
+    assert_eq!(text.find_word("X", &Direction::ALL).len(), 5);
+    let matches = text.find_word("XX", &Direction::ALL);
+    assert_eq!(matches.len(), 4);
+    assert!(matches.iter().all(|m| m.direction == Direction::E));
+}
+
+fn count_xmas(text: &Text2D) -> usize {
+    text.find_word("XMAS", &Direction::ALL).len()
 }
 
 #[test]
 fn test_count_xmas() {
-    assert_eq!(count_xmas(TEST_CASE), 18);
+    assert_eq!(count_xmas(&Text2D::new(TEST_CASE).unwrap()), 18);
 }
 
-fn check_x_mas(x: i32, y: i32, txt: &Text2D, count: &mut usize) {
-    let ch = txt.at(x, y).unwrap();
-    println!("({x}, {y}): {ch}");
-    if ch != 'A' {
-        return;
+/// Aho-Corasick automaton over a list of words, for finding all of them in one pass.
+pub struct Dictionary {
+    words: Vec<String>,
+    nodes: Vec<DictNode>,
+}
+
+#[derive(Default)]
+struct DictNode {
+    next: HashMap<char, usize>,
+    /// Node of the longest proper suffix of this node's prefix that's also in the trie.
+    fail: usize,
+    /// Indices of the words ending at this node, directly or through fail links.
+    words: Vec<usize>,
+}
+
+impl Dictionary {
+    pub fn new<S: AsRef<str>>(words: &[S]) -> Self {
+        // Repeated words are only looked for once.
+        let mut unique = HashSet::new();
+        let words: Vec<String> = words
+            .iter()
+            .map(|w| w.as_ref().to_string())
+            .filter(|w| unique.insert(w.clone()))
+            .collect();
+        let mut nodes = vec![DictNode::default()];
+        for (idx, word) in words.iter().enumerate() {
+            if word.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for ch in word.chars() {
+                node = match nodes[node].next.get(&ch) {
+                    Some(&next) => next,
+                    None => {
+                        nodes.push(DictNode::default());
+                        let next = nodes.len() - 1;
+                        nodes[node].next.insert(ch, next);
+                        next
+                    }
+                };
+            }
+            nodes[node].words.push(idx);
+        }
+
+        // Fail links, breadth first so a node's fail link is done before its children need it.
+        let mut queue: VecDeque<usize> = nodes[0].next.values().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            let children: Vec<(char, usize)> = nodes[node]
+                .next
+                .iter()
+                .map(|(&ch, &child)| (ch, child))
+                .collect();
+            for (ch, child) in children {
+                let mut fail = nodes[node].fail;
+                let child_fail = loop {
+                    if let Some(&next) = nodes[fail].next.get(&ch) {
+                        break next;
+                    }
+                    if fail == 0 {
+                        break 0;
+                    }
+                    fail = nodes[fail].fail;
+                };
+                nodes[child].fail = child_fail;
+                let inherited = nodes[child_fail].words.clone();
+                nodes[child].words.extend(inherited);
+                queue.push_back(child);
+            }
+        }
+        Dictionary { words, nodes }
     }
-    let top_left = txt.at(x - 1, y - 1);
-    let top_right = txt.at(x + 1, y - 1);
-    let bottom_left = txt.at(x - 1, y + 1);
-    let bottom_right = txt.at(x + 1, y + 1);
-    match (top_left, top_right, bottom_left, bottom_right) {
-        (Some('M'), Some('S'), Some('M'), Some('S')) => *count += 1,
-        (Some('S'), Some('M'), Some('S'), Some('M')) => *count += 1,
-        (Some('S'), Some('S'), Some('M'), Some('M')) => *count += 1,
-        (Some('M'), Some('M'), Some('S'), Some('S')) => *count += 1,
-        _ => ()
+
+    pub fn word(&self, idx: usize) -> &str {
+        &self.words[idx]
+    }
+
+    fn step(&self, mut node: usize, ch: char) -> usize {
+        loop {
+            if let Some(&next) = self.nodes[node].next.get(&ch) {
+                return next;
+            }
+            if node == 0 {
+                return 0;
+            }
+            node = self.nodes[node].fail;
+        }
+    }
+}
+
+/// A dictionary word found in a [Text2D].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DictionaryMatch {
+    /// Index of the word in the dictionary.
+    pub word: usize,
+    pub found: WordMatch,
+}
+
+pub struct DictionarySearch<'a> {
+    dictionary: &'a Dictionary,
+    pub matches: Vec<DictionaryMatch>,
+}
+
+impl DictionarySearch<'_> {
+    /// Dictionary words found at least once, in dictionary order.
+    pub fn found_words(&self) -> Vec<&str> {
+        self.words_where(true)
+    }
+
+    /// Dictionary words not found anywhere, in dictionary order.
+    pub fn missing_words(&self) -> Vec<&str> {
+        self.words_where(false)
+    }
+
+    fn words_where(&self, found: bool) -> Vec<&str> {
+        let found_idx: HashSet<usize> = self.matches.iter().map(|m| m.word).collect();
+        self.dictionary
+            .words
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| found_idx.contains(idx) == found)
+            .map(|(_, word)| word.as_str())
+            .collect()
     }
 }
 
-fn count_x_mas(i: &str) -> usize {
-    let text = Text2D::new(i);
-    let mut count = 0;
-    for y in 0..text.lines {
-        for x in 0..text.line_len {
-            check_x_mas(x, y, &text, &mut count);
+impl Text2D {
+    /// Finds every occurrence of every word in `dictionary`, in all 8 directions.
+    ///
+    /// Each line of the grid, in each direction, is fed through the automaton once,
+    /// so this costs the same no matter how many words there are.
+    /// Matches follow the same rules as [Text2D::find_word].
+    pub fn find_words<'a>(&self, dictionary: &'a Dictionary) -> DictionarySearch<'a> {
+        let longest = dictionary
+            .words
+            .iter()
+            .map(|word| word.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut matches = Vec::new();
+        let mut seen = HashSet::new();
+        for direction in Direction::ALL {
+            let (dx, dy) = direction.delta();
+            let period = self.period(direction);
+            let mut visited = HashSet::new();
+            for y in 0..self.lines {
+                for x in 0..self.line_len {
+                    let line: Vec<(i32, i32)> = match self.addressing {
+                        Addressing::Bounded => {
+                            // Lines start where stepping back leaves the grid.
+                            if self.at(x - dx, y - dy).is_some() {
+                                continue;
+                            }
+                            (0..)
+                                .map_while(|step| self.cell(x + dx * step, y + dy * step))
+                                .collect()
+                        }
+                        Addressing::Toroidal => {
+                            // Lines are loops, fed in from any of their cells, and once
+                            // more part of the way round so words can cross the start.
+                            if visited.contains(&(x, y)) {
+                                continue;
+                            }
+                            let len = (period + longest).saturating_sub(1) as i32;
+                            let line: Vec<(i32, i32)> = (0..len)
+                                .filter_map(|step| self.cell(x + dx * step, y + dy * step))
+                                .collect();
+                            visited.extend(line.iter().take(period).copied());
+                            line
+                        }
+                    };
+                    let mut node = 0;
+                    for (end, &(cx, cy)) in line.iter().enumerate() {
+                        node = dictionary.step(node, self.at(cx, cy).unwrap());
+                        for &word in &dictionary.nodes[node].words {
+                            let len = dictionary.words[word].chars().count();
+                            // Too long words would loop over themselves, and past the
+                            // loop's length the same starts come round again.
+                            if len > period || end + 1 - len >= period {
+                                continue;
+                            }
+                            let cells = line[end + 1 - len..=end].to_vec();
+                            let start = cells[0];
+                            if !seen.insert((word, canonical_cells(&cells))) {
+                                continue;
+                            }
+                            matches.push(DictionaryMatch {
+                                word,
+                                found: WordMatch {
+                                    start,
+                                    direction,
+                                    cells,
+                                },
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        DictionarySearch {
+            dictionary,
+            matches,
         }
     }
-    count
+}
+
+#[test]
+fn test_find_words() {
+    let text = Text2D::new(TEST_CASE).unwrap();
+    let words = [
+        "XMAS", "SAM", "MAS", "AM", "A", "XMASS", "ZZZ", "SAX", "MAS",
+    ];
+    let dictionary = Dictionary::new(&words);
+    let search = text.find_words(&dictionary);
+    assert_eq!(search.missing_words(), ["XMASS", "ZZZ"]);
+    assert_eq!(
+        search.found_words(),
+        ["XMAS", "SAM", "MAS", "AM", "A", "SAX"]
+    );
+
+    // Same matches as searching for every word on its own.
+    for (idx, word) in words.iter().enumerate().take(8) {
+        assert_eq!(dictionary.word(idx), *word);
+        let mut expected: Vec<_> = text
+            .find_word(word, &Direction::ALL)
+            .iter()
+            .map(|m| canonical_cells(&m.cells))
+            .collect();
+        let mut found: Vec<_> = search
+            .matches
+            .iter()
+            .filter(|m| m.word == idx)
+            .map(|m| canonical_cells(&m.found.cells))
+            .collect();
+        expected.sort();
+        found.sort();
+        assert_eq!(found, expected, "{word}");
+    }
+    assert_eq!(search.matches.iter().filter(|m| m.word == 0).count(), 18);
+    // The repeated MAS is only looked for once.
+    assert!(search.matches.iter().all(|m| m.word < 8));
+}
+
+/// A small 2D pattern of letters to look for in a [Text2D].
+/// Wildcard cells match any letter.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Template {
+    cells: Vec<Option<char>>,
+    width: i32,
+    height: i32,
+}
+
+impl Template {
+    /// Reads a template from its rows, with `.` marking wildcard cells.
+    ///
+    /// Panics if the rows aren't all the same width.
+    pub fn new(rows: &str) -> Self {
+        let rows: Vec<Vec<char>> = rows.lines().map(|row| row.chars().collect()).collect();
+        let width = rows.first().map_or(0, Vec::len);
+        assert!(
+            rows.iter().all(|row| row.len() == width),
+            "template rows must all be {width} wide"
+        );
+        Template {
+            cells: rows
+                .iter()
+                .flatten()
+                .map(|&ch| if ch == '.' { None } else { Some(ch) })
+                .collect(),
+            width: width as i32,
+            height: rows.len() as i32,
+        }
+    }
+
+    fn cell(&self, x: i32, y: i32) -> Option<char> {
+        self.cells[(x + y * self.width) as usize]
+    }
+
+    /// This template turned 90 degrees clockwise.
+    pub fn rotated(&self) -> Template {
+        let mut cells = Vec::with_capacity(self.cells.len());
+        for y in 0..self.width {
+            for x in 0..self.height {
+                cells.push(self.cell(y, self.height - 1 - x));
+            }
+        }
+        Template {
+            cells,
+            width: self.height,
+            height: self.width,
+        }
+    }
+
+    /// This template flipped left to right.
+    pub fn mirrored(&self) -> Template {
+        let mut cells = Vec::with_capacity(self.cells.len());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                cells.push(self.cell(self.width - 1 - x, y));
+            }
+        }
+        Template {
+            cells,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// This template and its distinct rotations.
+    pub fn rotations(&self) -> Vec<Template> {
+        let mut variants = vec![self.clone()];
+        for _ in 0..3 {
+            let next = variants.last().unwrap().rotated();
+            if !variants.contains(&next) {
+                variants.push(next);
+            }
+        }
+        variants
+    }
+
+    /// This template and its distinct rotations and mirror images.
+    pub fn variants(&self) -> Vec<Template> {
+        let mut variants = self.rotations();
+        for mirrored in self.mirrored().rotations() {
+            if !variants.contains(&mirrored) {
+                variants.push(mirrored);
+            }
+        }
+        variants
+    }
+
+    fn matches_at(&self, txt: &Text2D, x: i32, y: i32) -> bool {
+        (0..self.height).all(|ty| {
+            (0..self.width).all(|tx| match (self.cell(tx, ty), txt.at(x + tx, y + ty)) {
+                (None, found) => found.is_some(),
+                (expected, found) => expected == found,
+            })
+        })
+    }
+}
+
+impl Text2D {
+    /// Finds every placement of any of `templates`, as the position of the top left corner
+    /// and the index of the template. Each template that matches at a position counts.
+    ///
+    /// Placements wrap around the edges under [Addressing::Toroidal], but a template
+    /// larger than the grid never matches, as it would have to overlap itself.
+    pub fn find_templates(&self, templates: &[Template]) -> Vec<((i32, i32), usize)> {
+        let mut matches = Vec::new();
+        for y in 0..self.lines {
+            for x in 0..self.line_len {
+                for (idx, template) in templates.iter().enumerate() {
+                    if template.width > self.line_len || template.height > self.lines {
+                        continue;
+                    }
+                    if template.matches_at(self, x, y) {
+                        matches.push(((x, y), idx));
+                    }
+                }
+            }
+        }
+        matches
+    }
+}
+
+/// Two MAS in the shape of an X.
+const X_MAS: &str = r#"M.S
+.A.
+M.S"#;
+
+#[test]
+fn test_template_variants() {
+    let x_mas = Template::new(X_MAS);
+    let rotations = x_mas.rotations();
+    assert_eq!(rotations.len(), 4);
+    assert_eq!(rotations[1], Template::new("M.M\n.A.\nS.S"));
+    // Mirroring an X-MAS just gives one of its rotations.
+    assert_eq!(x_mas.variants(), rotations);
+
+    let ell = Template::new("X.\nXX");
+    assert_eq!(ell.rotated(), Template::new("XX\nX."));
+    assert_eq!(ell.mirrored(), Template::new(".X\nXX"));
+    assert_eq!(ell.variants().len(), 4);
+    let bar = Template::new("XMAS");
+    assert_eq!(bar.rotated(), Template::new("X\nM\nA\nS"));
+    assert_eq!(bar.variants().len(), 4);
+    assert_eq!(Template::new("A").variants().len(), 1);
+}
+
+#[test]
+fn test_find_templates() {
+    let text = Text2D::new(TEST_CASE).unwrap();
+    // XMAS as a template gives the same answer as the word search.
+    let xmas = Template::new("XMAS").variants();
+    assert_eq!(text.find_templates(&xmas).len(), 5 + 3);
+    let diagonal = Template::new("X...\n.M..\n..A.\n...S").variants();
+    assert_eq!(text.find_templates(&diagonal).len(), 10);
+
+    let matches = text.find_templates(&[Template::new("M.S\n.A.\nM.S")]);
+    assert_eq!(matches, [((1, 0), 0), ((1, 2), 0)]);
+}
+
+fn count_x_mas(text: &Text2D) -> usize {
+    text.find_templates(&Template::new(X_MAS).rotations()).len()
 }
 
 #[test]
 fn test_count_x_mas() {
-    assert_eq!(count_x_mas(TEST_CASE), 9);
+    assert_eq!(count_x_mas(&Text2D::new(TEST_CASE).unwrap()), 9);
+}
+
+#[test]
+fn test_toroidal() {
+    let row = Text2D::new("MASX").unwrap();
+    assert!(row.find_word("XMAS", &Direction::ALL).is_empty());
+    let row = row.with_addressing(Addressing::Toroidal);
+    // Diagonals on a single row run over the same cells as going east,
+    // so they're the same match.
+    let matches = row.find_word("XMAS", &Direction::ALL);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].cells, [(3, 0), (0, 0), (1, 0), (2, 0)]);
+    // Spelled out by going round twice, but that reuses the X.
+    assert!(row.find_word("XMASX", &Direction::ALL).is_empty());
+    assert!(row.find_word("XX", &Direction::ALL).is_empty());
+
+    let grid = Text2D::new("SMB\nBBA\nSMB").unwrap();
+    assert_eq!(count_x_mas(&grid), 0);
+    let grid = grid.with_addressing(Addressing::Toroidal);
+    assert_eq!(count_x_mas(&grid), 1);
+    assert!(grid.find_templates(&[Template::new("SMBS")]).is_empty());
+
+    // The dictionary search agrees with searching word by word.
+    for text in [TEST_CASE, "MASX", "SMB\nBBA\nSMB", "ABABA\nXXXXX"] {
+        let text = Text2D::new(text)
+            .unwrap()
+            .with_addressing(Addressing::Toroidal);
+        let words = ["XMAS", "XMASX", "ABA", "SAMXMAS", "XX", "MS", "A"];
+        let dictionary = Dictionary::new(&words);
+        let search = text.find_words(&dictionary);
+        for (idx, word) in words.iter().enumerate() {
+            let mut expected: Vec<_> = text
+                .find_word(word, &Direction::ALL)
+                .iter()
+                .map(|m| canonical_cells(&m.cells))
+                .collect();
+            let mut found: Vec<_> = search
+                .matches
+                .iter()
+                .filter(|m| m.word == idx)
+                .map(|m| canonical_cells(&m.found.cells))
+                .collect();
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected, "{word}");
+        }
+    }
+}
+
+/// Colors cycled through for consecutive matches when rendering.
+const ANSI_COLORS: [&str; 6] = ["31", "32", "33", "34", "35", "36"];
+const HTML_COLORS: [&str; 6] = [
+    "#d62728", "#2ca02c", "#ff7f0e", "#1f77b4", "#9467bd", "#17becf",
+];
+
+impl Text2D {
+    /// For each cell, the first of `matches` covering it.
+    fn highlights<I>(&self, matches: I) -> Vec<Option<usize>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[(i32, i32)]>,
+    {
+        let mut highlights = vec![None; self.chars.len()];
+        for (idx, cells) in matches.into_iter().enumerate() {
+            for &(x, y) in cells.as_ref() {
+                if let Some((x, y)) = self.cell(x, y) {
+                    let cell = &mut highlights[(x + y * self.line_len) as usize];
+                    cell.get_or_insert(idx);
+                }
+            }
+        }
+        highlights
+    }
+
+    /// Renders the grid for a terminal, each match in its own color and
+    /// every cell outside a match dimmed.
+    pub fn render_ansi<I>(&self, matches: I) -> String
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[(i32, i32)]>,
+    {
+        let highlights = self.highlights(matches);
+        let mut out = String::new();
+        for y in 0..self.lines {
+            for x in 0..self.line_len {
+                let ch = self.chars[(x + y * self.line_len) as usize];
+                let style = match highlights[(x + y * self.line_len) as usize] {
+                    Some(idx) => format!("1;{}", ANSI_COLORS[idx % ANSI_COLORS.len()]),
+                    None => "2".to_string(),
+                };
+                out.push_str(&format!("\x1b[{style}m{ch}\x1b[0m"));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Renders the grid as a standalone HTML page holding a single table,
+    /// styled the same way as [Text2D::render_ansi].
+    pub fn render_html<I>(&self, matches: I) -> String
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[(i32, i32)]>,
+    {
+        let highlights = self.highlights(matches);
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n\
+             td { font-family: monospace; text-align: center; width: 1.5em; }\n\
+             td.dim { color: #bbb; }\n",
+        );
+        for (idx, color) in HTML_COLORS.iter().enumerate() {
+            out.push_str(&format!(
+                "td.m{idx} {{ color: {color}; font-weight: bold; }}\n"
+            ));
+        }
+        out.push_str("</style>\n</head>\n<body>\n<table>\n");
+        for y in 0..self.lines {
+            out.push_str("<tr>");
+            for x in 0..self.line_len {
+                let class = match highlights[(x + y * self.line_len) as usize] {
+                    Some(idx) => format!("m{}", idx % HTML_COLORS.len()),
+                    None => "dim".to_string(),
+                };
+                let ch = match self.chars[(x + y * self.line_len) as usize] {
+                    '&' => "&amp;".to_string(),
+                    '<' => "&lt;".to_string(),
+                    '>' => "&gt;".to_string(),
+                    ch => ch.to_string(),
+                };
+                out.push_str(&format!("<td class=\"{class}\">{ch}</td>"));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n</body>\n</html>\n");
+        out
+    }
+}
+
+#[test]
+fn test_render() {
+    let text = Text2D::new("XMAS\nA<&B").unwrap();
+    let matches = text.find_word("XMAS", &Direction::ALL);
+    let ansi = text.render_ansi(&matches);
+    assert_eq!(
+        ansi,
+        "\x1b[1;31mX\x1b[0m\x1b[1;31mM\x1b[0m\x1b[1;31mA\x1b[0m\x1b[1;31mS\x1b[0m\n\
+         \x1b[2mA\x1b[0m\x1b[2m<\x1b[0m\x1b[2m&\x1b[0m\x1b[2mB\x1b[0m\n"
+    );
+    // Overlapping matches keep the color of the first one.
+    let both = [vec![(0, 0), (1, 0)], vec![(1, 0), (1, 1)]];
+    let ansi = text.render_ansi(&both);
+    assert!(ansi.starts_with("\x1b[1;31mX\x1b[0m\x1b[1;31mM\x1b[0m\x1b[2mA"));
+    assert!(ansi.contains("\x1b[1;32m<"));
+
+    let html = text.render_html(&matches);
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert_eq!(html.matches("<td class=\"m0\">").count(), 4);
+    assert_eq!(html.matches("<td class=\"dim\">").count(), 4);
+    assert!(html.contains("<td class=\"dim\">&lt;</td><td class=\"dim\">&amp;</td>"));
+}
+
+/// Xorshift PRNG, so generated puzzles can be reproduced from their seed.
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // An all zero state would stay zero forever.
+        XorShift(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// How many times to start a puzzle over before giving up.
+const GENERATE_ATTEMPTS: usize = 100;
+/// How many random spots to try for each word.
+const PLACE_ATTEMPTS: usize = 200;
+/// How many times to redraw noise that spelled out a word, before starting over.
+const NOISE_ROUNDS: usize = 100;
+
+/// A generated word search, and where its words were hidden.
+pub struct Puzzle {
+    pub text: String,
+    /// Word indices are into the generator's word list, once repeats are dropped.
+    pub placements: Vec<DictionaryMatch>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GenerateError {
+    /// No room was found for the word.
+    NoRoom(String),
+    /// The noise kept spelling out extra words.
+    Noisy,
+    /// Words kept spelling out extra words where they crossed.
+    Crossing,
+}
+
+/// Hides `words` in a new `width` x `height` grid, in random directions,
+/// and fills the rest with letters from `noise`.
+///
+/// Words may cross where their letters agree. The finished grid is searched to check
+/// that each word is found exactly once, where it was placed: noise spelling out a
+/// word is redrawn, and crossing words spelling out another start the grid over.
+/// Panics if `noise` is empty.
+pub fn generate_puzzle<S: AsRef<str>>(
+    words: &[S],
+    width: usize,
+    height: usize,
+    noise: &[char],
+    rng: &mut XorShift,
+) -> Result<Puzzle, GenerateError> {
+    assert!(!noise.is_empty(), "no noise letters to fill with");
+    let dictionary = Dictionary::new(words);
+    // Longest words first, while there's the most room.
+    let mut order: Vec<usize> = (0..dictionary.words.len()).collect();
+    order.sort_by_key(|&word| std::cmp::Reverse(dictionary.words[word].chars().count()));
+    // Why the latest attempt failed.
+    let mut failure = GenerateError::Noisy;
+    'attempt: for _ in 0..GENERATE_ATTEMPTS {
+        let mut cells = vec![None; width * height];
+        let mut placements = Vec::new();
+        for &word in &order {
+            match place(&dictionary.words[word], &mut cells, width, height, rng) {
+                Some(found) => placements.push(DictionaryMatch { word, found }),
+                None => {
+                    failure = GenerateError::NoRoom(dictionary.words[word].clone());
+                    continue 'attempt;
+                }
+            }
+        }
+
+        let placed: HashSet<(i32, i32)> = placements
+            .iter()
+            .flat_map(|m| m.found.cells.iter().copied())
+            .collect();
+        let mut letters: Vec<char> = cells
+            .iter()
+            .map(|cell| cell.unwrap_or_else(|| noise[rng.below(noise.len())]))
+            .collect();
+        for _ in 0..NOISE_ROUNDS {
+            let text: String = letters
+                .chunks(width)
+                .map(|row| row.iter().collect::<String>() + "\n")
+                .collect();
+            let search = Text2D::new(&text).unwrap().find_words(&dictionary);
+            let noisy: Vec<(i32, i32)> = search
+                .matches
+                .iter()
+                .flat_map(|m| m.found.cells.iter().copied())
+                .filter(|cell| !placed.contains(cell))
+                .collect();
+            if noisy.is_empty() {
+                // Only placed letters left, so anything extra won't go away with new noise.
+                let canonical = |m: &DictionaryMatch| (m.word, canonical_cells(&m.found.cells));
+                let found: HashSet<_> = search.matches.iter().map(canonical).collect();
+                let hidden: HashSet<_> = placements.iter().map(canonical).collect();
+                if search.matches.len() != placements.len() || found != hidden {
+                    failure = GenerateError::Crossing;
+                    continue 'attempt;
+                }
+                return Ok(Puzzle { text, placements });
+            }
+            for (x, y) in noisy {
+                letters[x as usize + y as usize * width] = noise[rng.below(noise.len())];
+            }
+        }
+        failure = GenerateError::Noisy;
+    }
+    Err(failure)
+}
+
+/// Writes `word` somewhere random in `cells`, where it doesn't clash with the letters
+/// already there, and isn't just lying on top of them.
+fn place(
+    word: &str,
+    cells: &mut [Option<char>],
+    width: usize,
+    height: usize,
+    rng: &mut XorShift,
+) -> Option<WordMatch> {
+    let letters: Vec<char> = word.chars().collect();
+    if cells.is_empty() {
+        return None;
+    }
+    for _ in 0..PLACE_ATTEMPTS {
+        let direction = Direction::ALL[rng.below(Direction::ALL.len())];
+        let (dx, dy) = direction.delta();
+        let start = (rng.below(width) as i32, rng.below(height) as i32);
+        let path: Vec<(i32, i32)> = (0..letters.len() as i32)
+            .map(|step| (start.0 + dx * step, start.1 + dy * step))
+            .collect();
+        let index = |(x, y): (i32, i32)| {
+            (x >= 0 && (x as usize) < width && y >= 0 && (y as usize) < height)
+                .then(|| x as usize + y as usize * width)
+        };
+        let Some(indices) = path
+            .iter()
+            .map(|&cell| index(cell))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let fits = indices
+            .iter()
+            .zip(&letters)
+            .all(|(&idx, &letter)| cells[idx].is_none_or(|ch| ch == letter));
+        let covered = indices.iter().all(|&idx| cells[idx].is_some());
+        if !fits || covered {
+            continue;
+        }
+        for (&idx, &letter) in indices.iter().zip(&letters) {
+            cells[idx] = Some(letter);
+        }
+        return Some(WordMatch {
+            start,
+            direction,
+            cells: path,
+        });
+    }
+    None
+}
+
+#[test]
+fn test_generate_puzzle() {
+    let words = ["XMAS", "SANTA", "ELF", "REINDEER", "TREE", "SLEIGH"];
+    let mut noise: Vec<char> = words.iter().flat_map(|word| word.chars()).collect();
+    noise.sort();
+    noise.dedup();
+    for seed in 1..20 {
+        let puzzle = generate_puzzle(&words, 10, 8, &noise, &mut XorShift::new(seed)).unwrap();
+        let text = Text2D::new(&puzzle.text).unwrap();
+        assert_eq!(puzzle.placements.len(), words.len());
+        for (idx, word) in words.iter().enumerate() {
+            let placed = puzzle.placements.iter().find(|m| m.word == idx).unwrap();
+            let found = text.find_word(word, &Direction::ALL);
+            assert_eq!(found.len(), 1, "{word}");
+            assert_eq!(
+                canonical_cells(&found[0].cells),
+                canonical_cells(&placed.found.cells)
+            );
+        }
+        assert_eq!(count_xmas(&text), 1);
+    }
+
+    // Crossing words that spell out more words are started over, not kept.
+    let words = ["AAB", "BCC", "CBA", "ACCA"];
+    let dictionary = Dictionary::new(&words);
+    for seed in 1..30 {
+        let Ok(puzzle) = generate_puzzle(&words, 5, 5, &['A', 'B', 'C'], &mut XorShift::new(seed))
+        else {
+            continue;
+        };
+        let search = Text2D::new(&puzzle.text).unwrap().find_words(&dictionary);
+        assert_eq!(search.matches.len(), words.len(), "seed {seed}");
+        assert!(search.missing_words().is_empty());
+    }
+
+    assert_eq!(
+        generate_puzzle(&["TOOLONG"], 3, 3, &['A'], &mut XorShift::new(1)).err(),
+        Some(GenerateError::NoRoom("TOOLONG".to_string()))
+    );
+    // A word inside another is always found twice.
+    assert_eq!(
+        generate_puzzle(&["ABA", "AB"], 4, 4, &['C'], &mut XorShift::new(1)).err(),
+        Some(GenerateError::Crossing)
+    );
+    // With nothing but its own letters as noise, a word still only shows up once.
+    let puzzle = generate_puzzle(&["ABA"], 4, 4, &['A', 'B'], &mut XorShift::new(7)).unwrap();
+    let text = Text2D::new(&puzzle.text).unwrap();
+    assert_eq!(text.find_word("ABA", &Direction::ALL).len(), 1);
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str| args.iter().find_map(|arg| arg.strip_prefix(name));
+    let read_words = |path: &str| -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|word| !word.is_empty())
+            .collect()
+    };
+    // `--generate=<width>x<height>` makes a new puzzle hiding the `--words=<path>` list,
+    // seeded with `--seed=<n>`.
+    if let Some(size) = flag("--generate=") {
+        let (width, height) = size
+            .split_once('x')
+            .expect("size should be <width>x<height>");
+        let words = read_words(flag("--words=").expect("--words=<path> to hide"));
+        let seed = flag("--seed=").map_or(1, |seed| seed.parse().unwrap());
+        let mut noise: Vec<char> = words.iter().flat_map(|word| word.chars()).collect();
+        noise.sort();
+        noise.dedup();
+        let puzzle = generate_puzzle(
+            &words,
+            width.parse().unwrap(),
+            height.parse().unwrap(),
+            &noise,
+            &mut XorShift::new(seed),
+        )
+        .unwrap();
+        print!("{}", puzzle.text);
+        return;
+    }
     let text = std::fs::read_to_string("day4/input.txt").unwrap();
+    let mut grid = match Text2D::new(&text) {
+        Ok(grid) => grid,
+        Err(RaggedLine {
+            line,
+            width,
+            expected,
+        }) => panic!("Line {line} is {width} wide, expected {expected}"),
+    };
+    // `--wrap` lets words and X-MAS continue across the edges.
+    if args.iter().any(|arg| arg == "--wrap") {
+        grid = grid.with_addressing(Addressing::Toroidal);
+    }
     // Part 1.
-    // println!("{}", count_xmas(&text));
+    println!("p1: {}", count_xmas(&grid));
     // Part 2.
-    println!("{}", count_x_mas(&text));
+    println!("p2: {}", count_x_mas(&grid));
+    let mut matches = grid.find_word("XMAS", &Direction::ALL);
+    // `--words=<path>` looks for every word in a word list, one per line.
+    if let Some(path) = flag("--words=") {
+        let words = read_words(path);
+        let dictionary = Dictionary::new(&words);
+        let search = grid.find_words(&dictionary);
+        for m in &search.matches {
+            let word = dictionary.word(m.word);
+            println!("{word}: {:?} going {:?}", m.found.start, m.found.direction);
+        }
+        println!("Missing: {:?}", search.missing_words());
+        matches = search.matches.into_iter().map(|m| m.found).collect();
+    }
+    // `--render=ansi` or `--render=html` shows the XMAS matches, or the word list's.
+    match flag("--render=") {
+        Some("ansi") => print!("{}", grid.render_ansi(&matches)),
+        Some("html") => print!("{}", grid.render_html(&matches)),
+        Some(other) => panic!("Unknown renderer {other}"),
+        None => {}
+    }
 }
@@ -1,6 +1,7 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Rule {
     before: u32,
     after: u32,
@@ -105,58 +106,403 @@ fn test_parse() {
     );
 }
 
-impl Rule {
-    fn check(&self, update: &Update) -> bool {
-        let mut seen_before = false;
-        let mut seen_after = false;
-        for p in &update.pages {
-            let p = *p;
-            if p == self.before {
-                // Check if we have seen the after page already...
-                if seen_after {
-                    return false;
-                }
-                seen_before = true;
-            } else if p == self.after {
-                // Check whether we have seen the before page *before* this page, 'cause this is after page.
-                // But if we only have the after page, but no before page, then this rule does not apply.
-                if seen_before {
-                    return true;
+/// Rules compiled for quick lookups, built once and shared by every update.
+///
+/// Every page in a rule gets a dense id, so per-page data, like where each page
+/// is in an update, can go in a `Vec` instead of being hashed for every rule.
+struct RuleSet {
+    /// Id of each page mentioned by a rule.
+    ids: HashMap<u32, usize>,
+    /// Page with each id.
+    pages: Vec<u32>,
+    /// Ids of the pages that have to come after each page, by page and without repeats.
+    after: Vec<Vec<usize>>,
+}
+
+impl RuleSet {
+    fn new(rules: &[Rule]) -> Self {
+        let mut set = RuleSet {
+            ids: HashMap::new(),
+            pages: Vec::new(),
+            after: Vec::new(),
+        };
+        for rule in rules {
+            let before = set.insert(rule.before);
+            let after = set.insert(rule.after);
+            set.after[before].push(after);
+        }
+        let RuleSet { pages, after, .. } = &mut set;
+        for after in after {
+            after.sort_unstable_by_key(|&id| pages[id]);
+            after.dedup();
+        }
+        set
+    }
+
+    /// Id of `page`, giving it the next one if it doesn't have one yet.
+    fn insert(&mut self, page: u32) -> usize {
+        *self.ids.entry(page).or_insert_with(|| {
+            self.pages.push(page);
+            self.after.push(Vec::new());
+            self.pages.len() - 1
+        })
+    }
+
+    /// Id of `page`, if any rule mentions it.
+    fn id(&self, page: u32) -> Option<usize> {
+        self.ids.get(&page).copied()
+    }
+
+    /// Pages that have to come after `page`, if they're in the same update.
+    fn after(&self, page: u32) -> impl Iterator<Item = u32> + '_ {
+        self.id(page)
+            .into_iter()
+            .flat_map(|id| self.after[id].iter().map(|&after| self.pages[after]))
+    }
+
+    /// Every page mentioned by a rule.
+    fn pages(&self) -> BTreeSet<u32> {
+        self.pages.iter().copied().collect()
+    }
+
+    /// Finds every tangle of rules that can't all hold at once,
+    /// as one cycle through each of them.
+    ///
+    /// A tangle is a strongly connected group of pages, so any update with two of its
+    /// pages may still be fine, as long as it doesn't contain a whole cycle.
+    /// A rule with the same page on both sides isn't a tangle: like [Update::check]
+    /// and [Update::correct], this ignores it.
+    fn cycles(&self) -> Vec<Cycle> {
+        let mut ids: Vec<usize> = (0..self.pages.len()).collect();
+        ids.sort_unstable_by_key(|&id| self.pages[id]);
+        let mut before = vec![Vec::new(); self.pages.len()];
+        for &id in &ids {
+            for &after in &self.after[id] {
+                before[after].push(id);
+            }
+        }
+
+        // Kosaraju's algorithm: pages in order of when their depth first search finished...
+        let mut finished = Vec::new();
+        let mut visited = vec![false; self.pages.len()];
+        for &root in &ids {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            let mut stack = vec![(root, 0)];
+            while let Some((id, next)) = stack.last_mut() {
+                let id = *id;
+                if let Some(&after) = self.after[id].get(*next) {
+                    *next += 1;
+                    if !visited[after] {
+                        visited[after] = true;
+                        stack.push((after, 0));
+                    }
                 } else {
-                    seen_after = true;
+                    finished.push(id);
+                    stack.pop();
                 }
             }
         }
-        // No pages or only one of the pages were present in this update.
-        true
+        // ...then, latest finished first, everything reaching them that isn't taken yet.
+        let mut component = vec![None; self.pages.len()];
+        let mut components = Vec::new();
+        for &root in finished.iter().rev() {
+            if component[root].is_some() {
+                continue;
+            }
+            let group = components.len();
+            let mut members = vec![root];
+            component[root] = Some(group);
+            let mut stack = vec![root];
+            while let Some(id) = stack.pop() {
+                for &prev in &before[id] {
+                    if component[prev].is_none() {
+                        component[prev] = Some(group);
+                        members.push(prev);
+                        stack.push(prev);
+                    }
+                }
+            }
+            components.push(members);
+        }
+
+        components
+            .iter()
+            .enumerate()
+            .filter(|(_, members)| members.len() > 1)
+            .map(|(group, members)| {
+                let start = *members.iter().min_by_key(|&&id| self.pages[id]).unwrap();
+                let ids = walk_back_to_cycle(start, |id| {
+                    before[id]
+                        .iter()
+                        .copied()
+                        .find(|&prev| prev != id && component[prev] == Some(group))
+                        .unwrap()
+                });
+                Cycle {
+                    pages: ids.into_iter().map(|id| self.pages[id]).collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Pages that each have to come before the next one, and the last one before the first.
+#[derive(Debug, PartialEq)]
+struct Cycle {
+    pages: Vec<u32>,
+}
+
+/// Follows `prev` back from `start` until it comes round to a node seen before,
+/// and returns that cycle in forward order.
+/// Every node on the way needs to have a node before it.
+fn walk_back_to_cycle<T: Copy + PartialEq>(start: T, prev: impl Fn(T) -> T) -> Vec<T> {
+    let mut walked = vec![start];
+    loop {
+        let node = prev(*walked.last().unwrap());
+        if let Some(pos) = walked.iter().position(|&seen| seen == node) {
+            let mut cycle = walked.split_off(pos);
+            cycle.reverse();
+            return cycle;
+        }
+        walked.push(node);
     }
 }
 
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for page in &self.pages {
+            write!(f, "{page} -> ")?;
+        }
+        write!(f, "{}", self.pages[0])
+    }
+}
+
+#[test]
+fn test_cycles() {
+    let (rules, _) = parse(TEST_DOC);
+    assert!(RuleSet::new(&rules).cycles().is_empty());
+
+    let (rules, _) = parse("1|2\n2|3\n3|1\n3|4\n4|5\n5|4\n6|6\n7|8\n\n1");
+    let cycles = RuleSet::new(&rules).cycles();
+    assert_eq!(cycles.len(), 2);
+    assert!(cycles.contains(&Cycle {
+        pages: vec![2, 3, 1]
+    }));
+    assert!(cycles.contains(&Cycle { pages: vec![5, 4] }));
+    assert_eq!(
+        cycles[0].to_string().matches("->").count(),
+        cycles[0].pages.len()
+    );
+
+    // A page having to come before itself is ignored everywhere,
+    // so there's nothing to validate, check or correct.
+    let (rules, mut updates) = parse("6|6\n6|7\n\n6,7\n7,6");
+    let rules = RuleSet::new(&rules);
+    assert!(rules.cycles().is_empty());
+    assert!(updates[0].check(&rules));
+    assert!(!updates[1].check(&rules));
+    updates[1].correct(&rules).unwrap();
+    assert_eq!(updates[1].pages, vec![6, 7]);
+    assert!(updates[1].check(&rules));
+    // Nor does it get in the way of a real cycle through it.
+    let (rules, _) = parse("1|1\n1|2\n2|1\n\n1");
+    assert_eq!(RuleSet::new(&rules).cycles(), [Cycle { pages: vec![2, 1] }]);
+}
+
 impl Update {
+    /// Ids of this update's pages that have rules, with where each one is, sorted by id.
+    /// Sized to the update rather than to every page in the rules.
+    fn ranks(&self, rules: &RuleSet) -> Vec<(usize, usize)> {
+        let mut ranks: Vec<(usize, usize)> = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &page)| Some((rules.id(page)?, idx)))
+            .collect();
+        ranks.sort_unstable();
+        ranks
+    }
+
+    /// Where the pages that have to come after the page at `idx` are in this update.
+    ///
+    /// Goes through either that page's rules or the pages of the update, whichever
+    /// there are fewer of, so it's never more lookups than the update is long.
+    fn after_positions<'a>(
+        &'a self,
+        rules: &'a RuleSet,
+        ranks: &'a [(usize, usize)],
+        idx: usize,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let after = rules
+            .id(self.pages[idx])
+            .map_or(&[][..], |id| rules.after[id].as_slice());
+        let by_rule = (after.len() <= self.pages.len()).then(|| {
+            after.iter().filter_map(|&after| {
+                let at = ranks.binary_search_by_key(&after, |&(id, _)| id).ok()?;
+                Some(ranks[at].1)
+            })
+        });
+        let by_page = (after.len() > self.pages.len()).then(|| {
+            self.pages
+                .iter()
+                .enumerate()
+                .filter(|&(_, &page)| {
+                    after
+                        .binary_search_by_key(&page, |&after| rules.pages[after])
+                        .is_ok()
+                })
+                .map(|(pos, _)| pos)
+        });
+        by_rule
+            .into_iter()
+            .flatten()
+            .chain(by_page.into_iter().flatten())
+    }
+
     /// Checks whether this update is in the correct order, given `rules`.
-    fn check(&self, rules: &[Rule]) -> bool {
-        for rule in rules {
-            if !rule.check(self) {
-                return false;
+    ///
+    /// Only looks at the rules for pages in this update, rather than every rule.
+    /// A rule with the same page on both sides doesn't say anything about the order.
+    fn check(&self, rules: &RuleSet) -> bool {
+        let ranks = self.ranks(rules);
+        (0..self.pages.len()).all(|idx| {
+            self.after_positions(rules, &ranks, idx)
+                .all(|pos| pos >= idx)
+        })
+    }
+
+    /// Lists every rule this update breaks, ordered by where the pages are.
+    fn violations(&self, rules: &RuleSet) -> Vec<Violation> {
+        let ranks = self.ranks(rules);
+        let mut violations = Vec::new();
+        for (idx, &page) in self.pages.iter().enumerate() {
+            for pos in self.after_positions(rules, &ranks, idx) {
+                if pos < idx {
+                    violations.push(Violation {
+                        rule: Rule {
+                            before: page,
+                            after: self.pages[pos],
+                        },
+                        before_pos: idx,
+                        after_pos: pos,
+                    });
+                }
             }
         }
-        true
+        violations.sort_by_key(|v| (v.after_pos, v.before_pos));
+        violations
+    }
+}
+
+/// A rule broken by an update, with where its pages are in the update.
+#[derive(Debug, PartialEq)]
+struct Violation {
+    rule: Rule,
+    before_pos: usize,
+    after_pos: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (at {}) should be before {} (at {})",
+            self.rule.before, self.before_pos, self.rule.after, self.after_pos
+        )
     }
 }
 
+#[test]
+fn test_violations() {
+    let (rules, updates) = parse(TEST_DOC);
+    let rules = RuleSet::new(&rules);
+    for update in &updates {
+        assert_eq!(update.violations(&rules).is_empty(), update.check(&rules));
+    }
+    assert_eq!(
+        updates[3].violations(&rules),
+        [Violation {
+            rule: Rule {
+                before: 97,
+                after: 75
+            },
+            before_pos: 1,
+            after_pos: 0,
+        }]
+    );
+    let violations = updates[5].violations(&rules);
+    assert_eq!(violations.len(), 4);
+    assert!(violations.iter().all(|v| v.before_pos > v.after_pos));
+    assert_eq!(
+        violations[0].to_string(),
+        "75 (at 2) should be before 13 (at 1)"
+    );
+
+    // A page isn't out of order with itself.
+    let (rules, updates) = parse("5|5\n5|1\n\n5,1\n1,5");
+    let rules = RuleSet::new(&rules);
+    assert!(updates[0].violations(&rules).is_empty());
+    assert_eq!(
+        updates[1].violations(&rules),
+        [Violation {
+            rule: Rule {
+                before: 5,
+                after: 1
+            },
+            before_pos: 1,
+            after_pos: 0,
+        }]
+    );
+}
+
 #[test]
 fn test_check() {
     let (rules, updates) = parse(TEST_DOC);
+    let rules = RuleSet::new(&rules);
     assert!(updates[0].check(&rules));
     assert!(updates[1].check(&rules));
     assert!(updates[2].check(&rules));
     assert!(!updates[3].check(&rules));
     assert!(!updates[4].check(&rules));
     assert!(!updates[5].check(&rules));
+
+    // Repeated rules and pages without any rules don't matter.
+    let rules = RuleSet::new(&[
+        Rule {
+            before: 1,
+            after: 2,
+        },
+        Rule {
+            before: 1,
+            after: 2,
+        },
+    ]);
+    assert_eq!(rules.after(1).collect::<Vec<_>>(), [2]);
+    assert_eq!(rules.after(2).count(), 0);
+    assert!(Update {
+        pages: vec![3, 1, 4, 2]
+    }
+    .check(&rules));
+    assert!(!Update {
+        pages: vec![2, 3, 1]
+    }
+    .check(&rules));
+
+    // Nor do rules with the same page on both sides, with either many rules or few.
+    let (rules, _) = parse("5|5\n5|1\n5|2\n5|3\n\n1");
+    let rules = RuleSet::new(&rules);
+    for pages in [vec![5], vec![5, 1], vec![4, 5, 3, 1, 2]] {
+        assert!(Update { pages }.check(&rules));
+    }
+    assert!(!Update { pages: vec![1, 5] }.check(&rules));
 }
 
 fn check_and_sum_middle_pages(i: &str) -> u32 {
     let (rules, updates) = parse(i);
+    let rules = RuleSet::new(&rules);
     updates
         .into_iter()
         .filter(|update| update.check(&rules))
@@ -179,79 +525,440 @@ impl Update {
     ///
     /// Claude cooked this one, I had heard of these things
     /// but didn't know you can apply them like this.
-    fn correct(&mut self, rules: &[Rule]) {
-        // Create adjacency list representation of the graph
-        let mut graph: HashMap<usize, Vec<usize>> = HashMap::new();
-        let mut in_degree: HashMap<usize, usize> = HashMap::new();
+    ///
+    /// If the rules for these pages go round in a cycle, there's no correct order,
+    /// and the update is left as it was.
+    fn correct(&mut self, rules: &RuleSet) -> Result<(), Cycle> {
+        let graph = self.graph(rules);
+        let sorted_indices = self.sort(&graph)?;
 
-        // Initialize in_degree for all pages
-        for idx in 0..self.pages.len() {
-            in_degree.entry(idx).or_insert(0);
+        // Create new pages array in correct order
+        let old_pages = self.pages.clone();
+        for (new_idx, &old_idx) in sorted_indices.iter().enumerate() {
+            self.pages[new_idx] = old_pages[old_idx];
         }
+        Ok(())
+    }
 
-        // Build the graph from applicable rules
-        for (i, &page_i) in self.pages.iter().enumerate() {
-            for (j, &page_j) in self.pages.iter().enumerate() {
-                if i == j {
-                    continue;
-                }
+    /// Adjacency list representation of the rules for pages in this update,
+    /// by index into the pages.
+    fn graph(&self, rules: &RuleSet) -> Vec<Vec<usize>> {
+        let ranks = self.ranks(rules);
+        let mut graph: Vec<Vec<usize>> = vec![Vec::new(); self.pages.len()];
+        for (i, edges) in graph.iter_mut().enumerate() {
+            edges.extend(self.after_positions(rules, &ranks, i).filter(|&j| j != i));
+            edges.sort_unstable_by_key(|&j| self.pages[j]);
+        }
+        graph
+    }
 
-                // Check if there's a rule saying page_i must come before page_j
-                if rules
-                    .iter()
-                    .any(|r| r.before == page_i && r.after == page_j)
-                {
-                    graph.entry(i).or_default().push(j);
-                    *in_degree.entry(j).or_insert(0) += 1;
-                }
+    /// Topologically sorts `graph` using Kahn's algorithm.
+    fn sort(&self, graph: &[Vec<usize>]) -> Result<Vec<usize>, Cycle> {
+        let mut reverse: Vec<Vec<usize>> = vec![Vec::new(); graph.len()];
+        let mut in_degree = vec![0usize; graph.len()];
+        for (i, edges) in graph.iter().enumerate() {
+            for &j in edges {
+                reverse[j].push(i);
+                in_degree[j] += 1;
             }
         }
 
-        // Perform topological sort using Kahn's algorithm
         let mut sorted_indices = Vec::new();
-        let mut queue: VecDeque<usize> = in_degree
-            .iter()
-            .filter(|(_, &degree)| degree == 0)
-            .map(|(&node, _)| node)
+        let mut queue: VecDeque<usize> = (0..graph.len())
+            .filter(|&node| in_degree[node] == 0)
             .collect();
 
         while let Some(node) = queue.pop_front() {
             sorted_indices.push(node);
 
-            if let Some(neighbors) = graph.get(&node) {
-                for &next in neighbors {
-                    *in_degree.get_mut(&next).unwrap() -= 1;
-                    if in_degree[&next] == 0 {
-                        queue.push_back(next);
+            for &next in &graph[node] {
+                in_degree[next] -= 1;
+                if in_degree[next] == 0 {
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        // Whatever is left still has a page before it that's also left, so walking back
+        // through those must end up going round a cycle.
+        if sorted_indices.len() < graph.len() {
+            let start = (0..graph.len()).find(|&idx| in_degree[idx] > 0).unwrap();
+            let cycle = walk_back_to_cycle(start, |idx| {
+                reverse[idx]
+                    .iter()
+                    .copied()
+                    .find(|&prev| in_degree[prev] > 0)
+                    .unwrap()
+            });
+            return Err(Cycle {
+                pages: cycle.iter().map(|&idx| self.pages[idx]).collect(),
+            });
+        }
+        Ok(sorted_indices)
+    }
+}
+
+#[test]
+fn test_correct() {
+    let (rules, mut updates) = parse(TEST_DOC);
+    let rules = RuleSet::new(&rules);
+    updates[3].correct(&rules).unwrap();
+    assert_eq!(updates[3].pages, vec![97, 75, 47, 61, 53]);
+
+    let (rules, mut updates) = parse("1|2\n2|3\n3|1\n4|1\n\n4,3,2,1\n4,2,1");
+    let rules = RuleSet::new(&rules);
+    assert_eq!(
+        updates[0].correct(&rules),
+        Err(Cycle {
+            pages: vec![1, 2, 3]
+        })
+    );
+    assert_eq!(updates[0].pages, vec![4, 3, 2, 1]);
+    // Without the 3, there's no cycle.
+    updates[1].correct(&rules).unwrap();
+    assert_eq!(updates[1].pages, vec![4, 1, 2]);
+}
+
+/// A page moved by [Update::correct_with_fewest_moves].
+///
+/// A move is taking the page out and putting it back among the pages that stay in
+/// order, so `from` can equal `to`: the page ends up at the same index, but on the
+/// other side of some of the kept pages, because other moved pages went past it.
+#[derive(Debug, PartialEq)]
+struct Move {
+    page: u32,
+    /// Index of the page before the update is corrected.
+    from: usize,
+    /// Index of the page after the update is corrected.
+    to: usize,
+}
+
+impl Update {
+    /// Corrects this update by moving as few pages as possible, and returns the moves.
+    /// The pages that don't move stay in the same order.
+    ///
+    /// Those are the most pages with no two of them out of order, following the rules
+    /// through the other pages of the update too. Pages being out of order is a partial
+    /// order, so that's its largest antichain, found from a maximum matching by
+    /// Kőnig's theorem.
+    fn correct_with_fewest_moves(&mut self, rules: &RuleSet) -> Result<Vec<Move>, Cycle> {
+        let mut graph = self.graph(rules);
+        // No point looking for the fewest moves if there's no correct order at all.
+        self.sort(&graph)?;
+        let n = self.pages.len();
+
+        // Which pages have to come after each page, directly or through others.
+        let mut reaches = vec![vec![false; n]; n];
+        for (start, reach) in reaches.iter_mut().enumerate() {
+            let mut stack = vec![start];
+            while let Some(page) = stack.pop() {
+                for &next in &graph[page] {
+                    if !reach[next] {
+                        reach[next] = true;
+                        stack.push(next);
                     }
                 }
             }
         }
+        // Page `i` comes first, but page `j` has to come before it.
+        let out_of_order = |i: usize, j: usize| i < j && reaches[j][i];
 
-        // Create new pages array in correct order
+        let mut matched_to = vec![None; n];
+        for i in 0..n {
+            augment(i, &mut vec![false; n], &mut matched_to, &out_of_order);
+        }
+        // Everything reachable from unmatched pages on the left, by alternating paths.
+        let mut left_seen = vec![false; n];
+        let mut right_seen = vec![false; n];
+        let mut stack: Vec<usize> = (0..n).filter(|&i| !matched_to.contains(&Some(i))).collect();
+        for &i in &stack {
+            left_seen[i] = true;
+        }
+        while let Some(i) = stack.pop() {
+            for j in 0..n {
+                if out_of_order(i, j) && !right_seen[j] {
+                    right_seen[j] = true;
+                    if let Some(k) = matched_to[j] {
+                        if !left_seen[k] {
+                            left_seen[k] = true;
+                            stack.push(k);
+                        }
+                    }
+                }
+            }
+        }
+        // Pages in neither half of the minimum vertex cover.
+        let kept: Vec<usize> = (0..n)
+            .filter(|&page| left_seen[page] && !right_seen[page])
+            .collect();
+
+        for pair in kept.windows(2) {
+            graph[pair[0]].push(pair[1]);
+        }
+        let sorted_indices = self
+            .sort(&graph)
+            .expect("pages left in order can't make a cycle");
+        let moves = sorted_indices
+            .iter()
+            .enumerate()
+            .filter(|(_, from)| kept.binary_search(from).is_err())
+            .map(|(to, &from)| Move {
+                page: self.pages[from],
+                from,
+                to,
+            })
+            .collect();
         let old_pages = self.pages.clone();
         for (new_idx, &old_idx) in sorted_indices.iter().enumerate() {
             self.pages[new_idx] = old_pages[old_idx];
         }
+        Ok(moves)
+    }
+}
+
+/// Looks for an augmenting path from `i`, as in Kuhn's algorithm for bipartite matching.
+fn augment(
+    i: usize,
+    seen: &mut [bool],
+    matched_to: &mut [Option<usize>],
+    edge: &impl Fn(usize, usize) -> bool,
+) -> bool {
+    for j in 0..seen.len() {
+        if !edge(i, j) || seen[j] {
+            continue;
+        }
+        seen[j] = true;
+        let free = match matched_to[j] {
+            None => true,
+            Some(k) => augment(k, seen, matched_to, edge),
+        };
+        if free {
+            matched_to[j] = Some(i);
+            return true;
+        }
     }
+    false
 }
 
 #[test]
-fn test_correct() {
+fn test_correct_with_fewest_moves() {
     let (rules, mut updates) = parse(TEST_DOC);
-    updates[3].correct(&rules);
-    assert_eq!(updates[3].pages, vec![97, 75, 47, 61, 53]);
+    let rules = RuleSet::new(&rules);
+    let moves = updates[5].correct_with_fewest_moves(&rules).unwrap();
+    assert_eq!(updates[5].pages, vec![97, 75, 47, 29, 13]);
+    // 47 has to come before 29 as well as 13 coming last, so two pages move.
+    // 29 ends up where it was, but now after 47.
+    assert_eq!(
+        moves,
+        [
+            Move {
+                page: 29,
+                from: 3,
+                to: 3
+            },
+            Move {
+                page: 13,
+                from: 1,
+                to: 4
+            }
+        ]
+    );
+
+    // Check against trying every order there is.
+    fn orders(pages: &[u32]) -> Vec<Vec<u32>> {
+        if pages.is_empty() {
+            return vec![vec![]];
+        }
+        let mut all = Vec::new();
+        for (idx, &page) in pages.iter().enumerate() {
+            let mut rest = pages.to_vec();
+            rest.remove(idx);
+            for mut order in orders(&rest) {
+                order.insert(0, page);
+                all.push(order);
+            }
+        }
+        all
+    }
+    fn common(a: &[u32], b: &[u32]) -> usize {
+        match (a.split_first(), b.split_first()) {
+            (Some((x, a_rest)), Some((y, b_rest))) if x == y => 1 + common(a_rest, b_rest),
+            (Some((_, a_rest)), Some((_, b_rest))) => common(a_rest, b).max(common(a, b_rest)),
+            _ => 0,
+        }
+    }
+    // The pages in an update don't have to be ordered by every rule, only by the
+    // rules for the pages it has.
+    let (tricky, tricky_updates) = parse("3|1\n3|4\n4|1\n5|2\n\n1,2,3\n1,2,4,3\n2,1,5,4,3");
+    let tricky = RuleSet::new(&tricky);
+    for (rules, updates) in [(&rules, updates), (&tricky, tricky_updates)] {
+        for mut update in updates {
+            let original = update.pages.clone();
+            let fewest = orders(&original)
+                .iter()
+                .filter(|order| {
+                    Update {
+                        pages: order.to_vec(),
+                    }
+                    .check(rules)
+                })
+                .map(|order| original.len() - common(&original, order))
+                .min()
+                .unwrap();
+            let moves = update.correct_with_fewest_moves(rules).unwrap();
+            assert!(update.check(rules), "{:?}", original);
+            assert_eq!(moves.len(), fewest, "{:?}", original);
+            for m in moves {
+                assert_eq!(original[m.from], m.page);
+                assert_eq!(update.pages[m.to], m.page);
+            }
+        }
+    }
+}
+
+/// What to draw with [RuleSet::to_dot].
+#[derive(Default)]
+struct DotOptions<'a> {
+    /// Only draw this update's pages, and the rules between them.
+    update: Option<&'a Update>,
+    /// Draw the rules broken by `update` in red.
+    violations: bool,
+    /// Line up the pages in the layers of their correct order, first layer first.
+    layers: bool,
+}
+
+impl Update {
+    /// Groups the pages by how many pages have to come before them, at most,
+    /// following the order found by [Update::correct].
+    fn layers(&self, rules: &RuleSet) -> Result<Vec<Vec<u32>>, Cycle> {
+        let graph = self.graph(rules);
+        let mut depth = vec![0; self.pages.len()];
+        let mut layers: Vec<Vec<u32>> = Vec::new();
+        for idx in self.sort(&graph)? {
+            for &next in &graph[idx] {
+                depth[next] = depth[next].max(depth[idx] + 1);
+            }
+            if layers.len() <= depth[idx] {
+                layers.resize(depth[idx] + 1, Vec::new());
+            }
+            layers[depth[idx]].push(self.pages[idx]);
+        }
+        Ok(layers)
+    }
+}
+
+impl RuleSet {
+    /// Writes the rules as a Graphviz DOT graph, with an edge from each page
+    /// to every page that has to come after it.
+    fn to_dot(&self, options: &DotOptions) -> String {
+        let pages: BTreeSet<u32> = match options.update {
+            Some(update) => update.pages.iter().copied().collect(),
+            None => self.pages(),
+        };
+        let broken: HashSet<(u32, u32)> = match options.update {
+            Some(update) if options.violations => update
+                .violations(self)
+                .iter()
+                .map(|v| (v.rule.before, v.rule.after))
+                .collect(),
+            _ => HashSet::new(),
+        };
+
+        let mut out = String::from("digraph rules {\n");
+        for &page in &pages {
+            out.push_str(&format!("    {page};\n"));
+        }
+        for &page in &pages {
+            for after in self.after(page) {
+                if !pages.contains(&after) {
+                    continue;
+                }
+                if broken.contains(&(page, after)) {
+                    out.push_str(&format!("    {page} -> {after} [color=red];\n"));
+                } else {
+                    out.push_str(&format!("    {page} -> {after};\n"));
+                }
+            }
+        }
+        if options.layers {
+            let update = Update {
+                pages: pages.iter().copied().collect(),
+            };
+            match update.layers(self) {
+                Ok(layers) => {
+                    for layer in layers {
+                        let layer: Vec<String> = layer.iter().map(u32::to_string).collect();
+                        out.push_str(&format!("    {{ rank=same; {}; }}\n", layer.join("; ")));
+                    }
+                }
+                Err(cycle) => out.push_str(&format!("    // No layers, rules go round {cycle}\n")),
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
 }
 
-fn correct_and_sum_middle_pages_of_corrected_updates(i: &str) -> u32 {
+#[test]
+fn test_to_dot() {
+    let (rules, updates) = parse(TEST_DOC);
+    let rules = RuleSet::new(&rules);
+    let dot = rules.to_dot(&DotOptions::default());
+    assert!(dot.starts_with("digraph rules {\n    13;\n"));
+    assert_eq!(dot.matches("->").count(), 21);
+
+    let dot = rules.to_dot(&DotOptions {
+        update: Some(&updates[3]),
+        violations: true,
+        layers: true,
+    });
+    assert_eq!(
+        dot,
+        "digraph rules {
+    47;
+    53;
+    61;
+    75;
+    97;
+    47 -> 53;
+    47 -> 61;
+    61 -> 53;
+    75 -> 47;
+    75 -> 53;
+    75 -> 61;
+    97 -> 47;
+    97 -> 53;
+    97 -> 61;
+    97 -> 75 [color=red];
+    { rank=same; 97; }
+    { rank=same; 75; }
+    { rank=same; 47; }
+    { rank=same; 61; }
+    { rank=same; 53; }
+}
+"
+    );
+
+    let (rules, updates) = parse("1|2\n2|1\n1|3\n\n1,2,3");
+    let dot = RuleSet::new(&rules).to_dot(&DotOptions {
+        update: Some(&updates[0]),
+        violations: false,
+        layers: true,
+    });
+    assert!(dot.contains("// No layers, rules go round"));
+    assert!(!dot.contains("color=red"));
+}
+
+fn correct_and_sum_middle_pages_of_corrected_updates(i: &str) -> Result<u32, Cycle> {
     let (rules, updates) = parse(i);
+    let rules = RuleSet::new(&rules);
     updates
         .into_iter()
         .filter(|update| !update.check(&rules))
         .map(|mut update| {
-            update.correct(&rules);
+            update.correct(&rules)?;
             let middle = update.pages.len() / 2;
-            update.pages[middle]
+            Ok(update.pages[middle])
         })
         .sum()
 }
@@ -259,15 +966,101 @@ fn correct_and_sum_middle_pages_of_corrected_updates(i: &str) -> u32 {
 #[test]
 fn test_correct_and_sum() {
     let sum = correct_and_sum_middle_pages_of_corrected_updates(TEST_DOC);
-    assert_eq!(sum, 123);
+    assert_eq!(sum, Ok(123));
 }
 
 fn main() {
     let input = std::fs::read_to_string("day5/input.txt").unwrap();
+    // `--dot` draws the whole rule set, and `--dot=<n>` just the rules for the nth update,
+    // with the ones it breaks highlighted, in layers. It prints nothing else, so the
+    // output can go straight to Graphviz.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(dot) = args.iter().find(|arg| arg.starts_with("--dot")) {
+        let (rules, updates) = parse(&input);
+        let rules = RuleSet::new(&rules);
+        let update = dot.strip_prefix("--dot=").map(|n| {
+            n.parse::<usize>()
+                .ok()
+                .and_then(|n| updates.get(n.checked_sub(1)?))
+                .unwrap_or_else(|| {
+                    panic!("--dot={n} should be an update from 1 to {}", updates.len())
+                })
+        });
+        print!(
+            "{}",
+            rules.to_dot(&DotOptions {
+                update,
+                violations: true,
+                layers: update.is_some(),
+            })
+        );
+        return;
+    }
     // Part 1.
     let sum = check_and_sum_middle_pages(&input);
     println!("p1: {sum}");
     // Part 2.
-    let sum = correct_and_sum_middle_pages_of_corrected_updates(&input);
-    println!("p2: {sum}");
+    match correct_and_sum_middle_pages_of_corrected_updates(&input) {
+        Ok(sum) => println!("p2: {sum}"),
+        Err(cycle) => println!("p2: can't order an update, its rules go round {cycle}"),
+    }
+    let (rules, updates) = parse(&input);
+    let rules = RuleSet::new(&rules);
+    // `--validate` lists the cycles in the whole rule set.
+    if args.iter().any(|arg| arg == "--validate") {
+        for cycle in rules.cycles() {
+            println!("Cycle: {cycle}");
+        }
+    }
+    // `--fewest-moves` corrects every invalid update by moving as few pages as possible.
+    if args.iter().any(|arg| arg == "--fewest-moves") {
+        for (idx, original) in updates.iter().enumerate() {
+            if original.check(&rules) {
+                continue;
+            }
+            let mut update = Update {
+                pages: original.pages.clone(),
+            };
+            match update.correct_with_fewest_moves(&rules) {
+                Ok(moves) => {
+                    println!("Update {} needs {} moves:", idx + 1, moves.len());
+                    for &Move { page, from, to } in &moves {
+                        if from != to {
+                            println!("  {page} from {from} to {to}");
+                            continue;
+                        }
+                        // Pages that stay in order, that this one ends up on the other side of.
+                        let passed: Vec<String> = original
+                            .pages
+                            .iter()
+                            .enumerate()
+                            .filter(|&(old, other)| {
+                                let new = update.pages.iter().position(|p| p == other).unwrap();
+                                moves.iter().all(|m| m.page != *other) && (old < from) != (new < to)
+                            })
+                            .map(|(_, other)| other.to_string())
+                            .collect();
+                        println!(
+                            "  {page} stays at {from}, but swaps sides with {}",
+                            passed.join(", ")
+                        );
+                    }
+                }
+                Err(cycle) => println!("Update {} can't be ordered: {cycle}", idx + 1),
+            }
+        }
+    }
+    // `--explain` lists the out of order pages in every invalid update.
+    if args.iter().any(|arg| arg == "--explain") {
+        for (idx, update) in updates.iter().enumerate() {
+            let violations = update.violations(&rules);
+            if violations.is_empty() {
+                continue;
+            }
+            println!("Update {} is out of order:", idx + 1);
+            for violation in violations {
+                println!("  {violation}");
+            }
+        }
+    }
 }